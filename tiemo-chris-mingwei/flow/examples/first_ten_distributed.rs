@@ -2,6 +2,7 @@ use std::cell::RefCell;
 
 use hydro_deploy::{Deployment, HydroflowCrate};
 use hydroflow_plus_cli_integration::{DeployClusterSpec, DeployProcessSpec};
+use stageleft::RuntimeData;
 
 #[tokio::main]
 async fn main() {
@@ -38,6 +39,7 @@ async fn main() {
                 )
             ]
         }),
+        RuntimeData::new("FAKE"),
     );
 
     let mut deployment = deployment.into_inner();
@@ -2,6 +2,7 @@ use std::{sync::Arc, cell::RefCell};
 
 use hydro_deploy::{gcp::GCPNetwork, Deployment, HydroflowCrate};
 use hydroflow_plus_cli_integration::{DeployClusterSpec, DeployProcessSpec};
+use stageleft::RuntimeData;
 use tokio::sync::RwLock;
 
 #[tokio::main]
@@ -43,6 +44,7 @@ async fn main() {
                 deployment.borrow_mut().add_service(HydroflowCrate::new(".", host).bin("first_ten_distributed"))
             ]
         }),
+        RuntimeData::new("FAKE"),
     );
 
     let mut deployment = deployment.into_inner();
@@ -0,0 +1,202 @@
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use flow::first_ten_distributed::{
+    ClientRequest, EntityId, Key, LockConfig, LockInput, LockManager, LockResponse, MachineId,
+    TransactionId,
+};
+use flow::hierarchy::EntityPath;
+use flow::lock_mode::LockMode;
+use flow::lock_table::{Fairness, LockTable};
+use flow::workload::{WorkloadConfig, WorkloadStats};
+use hydro_deploy::{Deployment, HydroflowCrate};
+use hydroflow_plus::futures::{self, StreamExt};
+use hydroflow_plus_cli_integration::{DeployClusterSpec, DeployProcessSpec};
+use stageleft::RuntimeData;
+
+/// How many requests queue up on the one hot key.
+const WAITERS: usize = 50_000;
+
+/// Every fifth request is exclusive, the rest shared.
+fn mode(i: usize) -> LockMode {
+    match i % 5 {
+        0 => LockMode::X,
+        _ => LockMode::S,
+    }
+}
+
+fn per_op(elapsed: Duration, ops: usize) -> Duration {
+    elapsed / ops.max(1) as u32
+}
+
+/// Queues `WAITERS` requests behind an exclusive holder of one key, withdraws every other one of
+/// them, and then releases grants until the queue is gone.
+fn table(fairness: Fairness) {
+    let mut table = LockTable::<u32, usize>::new(fairness);
+    table.acquire(0, 0, LockMode::X);
+
+    let start = Instant::now();
+    for i in 1..=WAITERS {
+        table.acquire(0, i, mode(i));
+    }
+    let queued = start.elapsed();
+
+    let start = Instant::now();
+    for i in (1..=WAITERS).step_by(2) {
+        table.cancel(&0, &i);
+    }
+    let cancelled = start.elapsed();
+
+    let start = Instant::now();
+    let mut holders = VecDeque::from([0]);
+    let mut releases = 0;
+    while let Some(holder) = holders.pop_front() {
+        let granted = table.release(&0, &holder).unwrap();
+        holders.extend(granted.into_iter().map(|(_, holder, _)| holder));
+        releases += 1;
+    }
+    let drained = start.elapsed();
+    assert_eq!(table.entities(), 0);
+
+    println!(
+        "{:?}: queue {:?}/op, cancel {:?}/op, release {:?}/op",
+        fairness,
+        per_op(queued, WAITERS),
+        per_op(cancelled, WAITERS / 2),
+        per_op(drained, releases),
+    );
+}
+
+fn key(transaction_id: usize) -> Key {
+    Key {
+        transaction_id: TransactionId(transaction_id),
+        machine_id: MachineId(0),
+    }
+}
+
+/// The same hot key through a whole lock service member: every transaction acquires the key and
+/// commits as soon as it is granted, one batch per request.
+fn manager(fairness: Fairness) {
+    let mut manager = LockManager::new(LockConfig {
+        fairness,
+        ..Default::default()
+    });
+    let entity = EntityPath(vec![EntityId(0), EntityId(0)]);
+
+    let start = Instant::now();
+    for i in 0..WAITERS {
+        manager.apply_batch(vec![LockInput::Request(
+            0,
+            key(i),
+            ClientRequest::Acquire {
+                entity: entity.clone(),
+                mode: mode(i),
+            },
+        )]);
+    }
+    let queued = start.elapsed();
+
+    let start = Instant::now();
+    let mut granted = VecDeque::from([key(0)]);
+    let mut commits = 0;
+    while let Some(key) = granted.pop_front() {
+        manager.apply_batch(vec![LockInput::Request(0, key, ClientRequest::Commit)]);
+        granted.extend(
+            manager
+                .outbox
+                .iter()
+                .filter_map(|(_, response)| match response {
+                    LockResponse::Granted { key, .. } => Some(*key),
+                    _ => None,
+                }),
+        );
+        commits += 1;
+    }
+    let drained = start.elapsed();
+    assert_eq!(manager.table.table().entities(), 0);
+
+    println!(
+        "{:?} through the manager: acquire {:?}/op, commit {:?}/op",
+        fairness,
+        per_op(queued, WAITERS),
+        per_op(drained, commits),
+    );
+}
+
+/// The same hot key through the deployed lock service, dataflow and network included: the
+/// workload of three client members, each running `WORKLOAD_CLIENTS` transactions at once that
+/// lock only the one entity, with every fifth lock exclusive.
+async fn service(fairness: &str) {
+    // the members inherit these along with any other `WORKLOAD_*` variables of this process
+    std::env::set_var("WORKLOAD_ENTITIES", "1");
+    std::env::set_var("WORKLOAD_TRANSACTION_LENGTH", "1");
+    std::env::set_var("WORKLOAD_READ_FRACTION", "0.8");
+    std::env::set_var("WORKLOAD_FAIRNESS", fairness);
+    let config = WorkloadConfig::from_env();
+    let members = 3;
+
+    let mut deployment = Deployment::new();
+    let localhost = deployment.Localhost();
+    let deployment = RefCell::new(deployment);
+    let service = || {
+        deployment.borrow_mut().add_service(
+            HydroflowCrate::new(".", localhost.clone())
+                .bin("workload")
+                .profile("release"),
+        )
+    };
+
+    let flow = hydroflow_plus::FlowBuilder::new();
+    let clients = flow::workload::workload(
+        &flow,
+        &DeployProcessSpec::new(service),
+        &DeployClusterSpec::new(|| (0..members).map(|_| service()).collect()),
+        RuntimeData::new("FAKE"),
+    );
+
+    let mut deployment = deployment.into_inner();
+    deployment.deploy().await.unwrap();
+
+    let mut stdout = futures::stream::select_all(
+        futures::future::join_all(clients.members().iter().map(|member| member.stdout())).await,
+    );
+
+    deployment.start().await.unwrap();
+
+    let mut total = WorkloadStats::default();
+    let mut reported = 0;
+    tokio::time::timeout(config.duration + Duration::from_secs(30), async {
+        while reported < members {
+            let line = stdout.next().await.unwrap();
+            if let Some(stats) = WorkloadStats::from_line(&line) {
+                total.merge(&stats);
+                reported += 1;
+            }
+        }
+    })
+    .await
+    .expect("every client member reports its stats");
+    println!("{:?} through the service: {}", config.lock.fairness, total);
+}
+
+#[tokio::main]
+async fn main() {
+    println!("{} waiters on one key", WAITERS);
+    for fairness in [
+        Fairness::Fifo,
+        Fairness::CompatibleJump { max_bypass: 16 },
+        Fairness::Priority { max_bypass: 16 },
+    ] {
+        table(fairness);
+        manager(fairness);
+    }
+
+    println!(
+        "{} clients per member on one key",
+        WorkloadConfig::from_env().clients
+    );
+    for fairness in ["fifo", "jump:16", "priority:16"] {
+        service(fairness).await;
+    }
+}
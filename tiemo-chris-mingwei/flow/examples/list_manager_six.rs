@@ -1,92 +1,40 @@
-use std::collections::VecDeque;
-
+use flow::lock_mode::LockMode;
+use flow::lock_table::{AcquireOutcome, LockTable};
 use hydroflow::hydroflow_syntax;
 
 /// Each lock has owners and waiters.
 
-#[derive(Clone, Copy, Debug, PartialEq, PartialOrd, Eq, Ord, Hash)]
-pub enum LockMode {
-    NL,
-    IS,
-    IX,
-    S,
-    SIX,
-    X,
-}
-impl LockMode {
-    pub fn compatible(self, other: Self) -> bool {
-        match (self, other) {
-            (LockMode::NL, LockMode::NL) => true,
-            (LockMode::NL, LockMode::IS) => true,
-            (LockMode::NL, LockMode::IX) => true,
-            (LockMode::NL, LockMode::S) => true,
-            (LockMode::NL, LockMode::SIX) => true,
-            (LockMode::NL, LockMode::X) => true,
-            (LockMode::IS, LockMode::NL) => false,
-            (LockMode::IS, LockMode::IS) => true,
-            (LockMode::IS, LockMode::IX) => true,
-            (LockMode::IS, LockMode::S) => true,
-            (LockMode::IS, LockMode::SIX) => true,
-            (LockMode::IS, LockMode::X) => false,
-            (LockMode::IX, LockMode::NL) => true,
-            (LockMode::IX, LockMode::IS) => true,
-            (LockMode::IX, LockMode::IX) => true,
-            (LockMode::IX, LockMode::S) => false,
-            (LockMode::IX, LockMode::SIX) => false,
-            (LockMode::IX, LockMode::X) => false,
-            (LockMode::S, LockMode::NL) => true,
-            (LockMode::S, LockMode::IS) => true,
-            (LockMode::S, LockMode::IX) => false,
-            (LockMode::S, LockMode::S) => true,
-            (LockMode::S, LockMode::SIX) => false,
-            (LockMode::S, LockMode::X) => false,
-            (LockMode::SIX, LockMode::NL) => true,
-            (LockMode::SIX, LockMode::IS) => true,
-            (LockMode::SIX, LockMode::IX) => false,
-            (LockMode::SIX, LockMode::S) => false,
-            (LockMode::SIX, LockMode::SIX) => false,
-            (LockMode::SIX, LockMode::X) => false,
-            (LockMode::X, LockMode::NL) => true,
-            (LockMode::X, LockMode::IS) => false,
-            (LockMode::X, LockMode::IX) => false,
-            (LockMode::X, LockMode::S) => false,
-            (LockMode::X, LockMode::SIX) => false,
-            (LockMode::X, LockMode::X) => false,
-        }
-    }
-}
-
-#[derive(Clone, Copy, Debug, PartialEq, PartialOrd, Eq, Ord, Hash)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub struct LockRequest {
-    client_id: &'static str,
+    client_id: String,
     requested_state: LockMode,
 }
 
+/// How many locks the table keeps queues for; acquires of further locks are turned away.
+const MAX_LOCKS: usize = 1024;
+
 pub fn main() {
-    let (items_send, items_recv) =
-        hydroflow::util::unbounded_channel::<(&'static str, LockRequest)>();
+    let (items_send, items_recv) = hydroflow::util::unbounded_channel::<(String, LockRequest)>();
 
+    // the table lives outside of the ticks, so a request only touches the queue of its own lock and
+    // only the grants it causes are printed
+    let mut table = LockTable::<String, String>::default();
     let mut flow = hydroflow_syntax! {
         source_stream(items_recv)
-            -> fold_keyed::<'static>(|| VecDeque::<LockRequest>::new(), |queue: &mut VecDeque<_>, req: LockRequest| {
+            -> flat_map(move |(lock_id, req): (String, LockRequest)| {
                 if LockMode::NL == req.requested_state {
-                    queue.retain(|queue_req| queue_req.client_id != req.client_id);
+                    // drops the lock's queue once nobody holds or waits for it
+                    table.release(&lock_id, &req.client_id).unwrap_or_default()
+                } else if table.queue(&lock_id).is_none() && table.entities() >= MAX_LOCKS {
+                    println!("backpressure: {} not locked for {}", lock_id, req.client_id);
+                    Vec::new()
+                } else if table.acquire(lock_id.clone(), req.client_id.clone(), req.requested_state) == AcquireOutcome::Granted {
+                    vec![(lock_id, req.client_id, req.requested_state)]
                 } else {
-                    queue.push_back(req);
-                }
-            })
-            -> flat_map(|(lock_id, queue)| {
-                let mut output = Vec::new();
-                let mut lock_state = LockMode::NL;
-                for locked in queue {
-                    if !lock_state.compatible(locked.requested_state) {
-                        break;
-                    }
-                    lock_state = locked.requested_state;
-                    output.push(locked);
+                    Vec::new()
                 }
-                output.into_iter().map(move |locked| (lock_id, locked))
             })
+            -> map(|(lock_id, client_id, requested_state)| (lock_id, LockRequest { client_id, requested_state }))
             -> for_each(|x| println!("{}: {:?}", context.current_tick(), x));
     };
 
@@ -94,63 +42,63 @@ pub fn main() {
         (
             "foo_lock",
             LockRequest {
-                client_id: "joe",
+                client_id: "joe".to_owned(),
                 requested_state: LockMode::S,
             },
         ),
         (
             "foo_lock",
             LockRequest {
-                client_id: "shadaj",
+                client_id: "shadaj".to_owned(),
                 requested_state: LockMode::S,
             },
         ),
         (
             "foo_lock",
             LockRequest {
-                client_id: "mingwei",
+                client_id: "mingwei".to_owned(),
                 requested_state: LockMode::X,
             },
         ),
         (
             "foo_lock",
             LockRequest {
-                client_id: "shadaj",
+                client_id: "shadaj".to_owned(),
                 requested_state: LockMode::NL,
             },
         ),
         (
             "foo_lock",
             LockRequest {
-                client_id: "chris",
+                client_id: "chris".to_owned(),
                 requested_state: LockMode::S,
             },
         ),
         (
             "foo_lock",
             LockRequest {
-                client_id: "tiemo",
+                client_id: "tiemo".to_owned(),
                 requested_state: LockMode::S,
             },
         ),
         (
             "foo_lock",
             LockRequest {
-                client_id: "joe",
+                client_id: "joe".to_owned(),
                 requested_state: LockMode::NL,
             },
         ),
         (
             "foo_lock",
             LockRequest {
-                client_id: "mingwei",
+                client_id: "mingwei".to_owned(),
                 requested_state: LockMode::NL,
             },
         ),
     ];
 
-    for req in reqs {
-        items_send.send(req).unwrap();
+    for (lock_id, req) in reqs {
+        items_send.send((lock_id.to_owned(), req)).unwrap();
 
         flow.run_available();
     }
@@ -163,7 +111,6 @@ pub struct Key {
     machine_id: usize,
 }
 
-
 // Client entry point: source of transaction commands (transaction id, command type)
 /*
 begin txn: (0, begin_txn)
@@ -173,4 +120,4 @@ commit txn: (transaction id, commit)
 abort txn: (transaction id, abort)
 */
 
-// client to socket mapping ()
\ No newline at end of file
+// client to socket mapping ()
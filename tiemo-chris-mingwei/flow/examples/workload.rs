@@ -0,0 +1,66 @@
+use std::cell::RefCell;
+use std::time::Duration;
+
+use flow::workload::{WorkloadConfig, WorkloadStats};
+use hydro_deploy::{Deployment, HydroflowCrate};
+use hydroflow_plus::futures::{self, StreamExt};
+use hydroflow_plus_cli_integration::{DeployClusterSpec, DeployProcessSpec};
+use stageleft::RuntimeData;
+
+/// Runs the workload described by the `WORKLOAD_*` variables (see [`WorkloadConfig::from_env`])
+/// against a lock service of `WORKLOAD_MEMBERS` members, 3 by default, and prints what all of its
+/// client members did together.
+#[tokio::main]
+async fn main() {
+    let config = WorkloadConfig::from_env();
+    let members = std::env::var("WORKLOAD_MEMBERS")
+        .ok()
+        .and_then(|members| members.parse().ok())
+        .unwrap_or(3);
+
+    let mut deployment = Deployment::new();
+    let localhost = deployment.Localhost();
+    let deployment = RefCell::new(deployment);
+    // the members inherit the `WORKLOAD_*` variables of this process
+    let service = || {
+        deployment.borrow_mut().add_service(
+            HydroflowCrate::new(".", localhost.clone())
+                .bin("workload")
+                .profile("release"),
+        )
+    };
+
+    let flow = hydroflow_plus::FlowBuilder::new();
+    let clients = flow::workload::workload(
+        &flow,
+        &DeployProcessSpec::new(service),
+        &DeployClusterSpec::new(|| (0..members).map(|_| service()).collect()),
+        RuntimeData::new("FAKE"),
+    );
+
+    let mut deployment = deployment.into_inner();
+    deployment.deploy().await.unwrap();
+
+    let mut stdout = futures::stream::select_all(
+        futures::future::join_all(clients.members().iter().map(|member| member.stdout())).await,
+    );
+
+    deployment.start().await.unwrap();
+
+    println!("{:?} on {} members", config, members);
+    let mut total = WorkloadStats::default();
+    let mut reported = 0;
+    tokio::time::timeout(config.duration + Duration::from_secs(30), async {
+        while reported < members {
+            let line = stdout.next().await.unwrap();
+            if let Some(stats) = WorkloadStats::from_line(&line) {
+                println!("member {}: {}", reported, stats);
+                total.merge(&stats);
+                reported += 1;
+            }
+        }
+    })
+    .await
+    .expect("every client member reports its stats");
+    println!("total: {}", total);
+}
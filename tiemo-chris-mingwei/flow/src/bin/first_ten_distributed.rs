@@ -1,8 +1,15 @@
 #[tokio::main]
 async fn main() {
+    // every member runs the demo, but only the client members serve its requests
+    let (client, client_ports) = flow::lock_client::LockClient::new();
+    tokio::spawn(flow::first_ten_distributed::deadlock_demo(client));
+
     hydroflow_plus::util::cli::launch(|ports| {
-        let hf = flow::first_ten_distributed::first_ten_distributed_runtime!(&ports);
-        hf.meta_graph().unwrap().open_mermaid(&Default::default()).unwrap();
+        let hf = flow::first_ten_distributed::first_ten_distributed_runtime!(&ports, client_ports);
+        hf.meta_graph()
+            .unwrap()
+            .open_mermaid(&Default::default())
+            .unwrap();
         hf
     })
     .await;
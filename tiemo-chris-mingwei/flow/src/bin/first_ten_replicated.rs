@@ -0,0 +1,11 @@
+#[tokio::main]
+async fn main() {
+    // every member runs the demo, but only the client members serve its requests
+    let (client, client_ports) = flow::lock_client::LockClient::new();
+    tokio::spawn(flow::first_ten_distributed::deadlock_demo(client));
+
+    hydroflow_plus::util::cli::launch(|ports| {
+        flow::replicated::first_ten_replicated_runtime!(&ports, client_ports)
+    })
+    .await;
+}
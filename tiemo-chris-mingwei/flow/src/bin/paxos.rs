@@ -0,0 +1,4 @@
+#[tokio::main]
+async fn main() {
+    hydroflow_plus::util::cli::launch(|ports| flow::paxos::paxos_runtime!(&ports)).await;
+}
@@ -0,0 +1,11 @@
+#[tokio::main]
+async fn main() {
+    // every member runs the workload, but only the client members serve its requests
+    let (client, client_ports) = flow::lock_client::LockClient::new();
+    tokio::spawn(flow::workload::workload_demo(client));
+
+    hydroflow_plus::util::cli::launch(|ports| {
+        flow::workload::workload_runtime!(&ports, client_ports)
+    })
+    .await;
+}
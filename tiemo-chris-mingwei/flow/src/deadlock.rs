@@ -0,0 +1,398 @@
+use std::cmp::Reverse;
+use std::collections::{BTreeMap, BTreeSet};
+
+use serde::{Deserialize, Serialize};
+
+use crate::lock_table::LockQueue;
+
+/// Which transaction of a deadlock cycle gets aborted.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum VictimPolicy {
+    /// The transaction that started last, i.e. the largest id.
+    Youngest,
+    /// The transaction holding the fewest locks, so the least work is thrown away. Ties go to
+    /// the youngest.
+    FewestLocks,
+}
+
+/// How the lock manager keeps deadlocks from forming at all, using the holders' order as their
+/// age: the smaller one started first and is the older.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Prevention {
+    /// An older transaction may wait for a younger one, but a younger one that would wait for an
+    /// older one is aborted instead.
+    WaitDie,
+    /// A younger transaction may wait for an older one, but an older one that would wait for a
+    /// younger one aborts ("wounds") it instead.
+    WoundWait,
+}
+
+impl Prevention {
+    /// The transaction to abort so that `waiter` does not wait for `blocker`, if any.
+    pub fn victim<T: Ord>(self, waiter: T, blocker: T) -> Option<T> {
+        match self {
+            Prevention::WaitDie if waiter > blocker => Some(waiter),
+            Prevention::WoundWait if waiter < blocker => Some(blocker),
+            _ => None,
+        }
+    }
+}
+
+/// Who waits for whom: an edge `a -> b` means `a` cannot make progress until `b` releases a lock.
+#[derive(Clone, Debug)]
+pub struct WaitsForGraph<T> {
+    edges: BTreeMap<T, BTreeSet<T>>,
+}
+
+impl<T> Default for WaitsForGraph<T> {
+    fn default() -> Self {
+        Self {
+            edges: BTreeMap::new(),
+        }
+    }
+}
+
+impl<T: Clone + Ord> WaitsForGraph<T> {
+    /// Builds the graph from the owner and waiter state of every lock queue.
+    pub fn from_queues<'q, E: 'q>(queues: impl Iterator<Item = (&'q E, &'q LockQueue<T>)>) -> Self
+    where
+        T: 'q,
+    {
+        let mut graph = Self::default();
+        for (_, queue) in queues {
+            // a conversion waits on every other holder whose grant conflicts with its target
+            for (converter, target) in queue.conversions() {
+                for (holder, held) in queue.holders() {
+                    if holder != converter && !held.compatible(*target) {
+                        graph.add_edge(converter.clone(), holder.clone());
+                    }
+                }
+            }
+
+            // a waiter waits on conflicting holders and on the closest request served before it
+            // that holds up the rest
+            let mut ahead = queue
+                .conversions()
+                .iter()
+                .map(|(converter, _)| converter)
+                .collect::<Vec<_>>();
+            for (waiter, mode, holds_up) in queue.queued() {
+                for (holder, held) in queue.holders() {
+                    if !held.compatible(mode) {
+                        graph.add_edge(waiter.clone(), holder.clone());
+                    }
+                }
+                for blocker in &ahead {
+                    graph.add_edge(waiter.clone(), (*blocker).clone());
+                }
+                if holds_up {
+                    ahead = vec![waiter];
+                }
+            }
+        }
+        graph
+    }
+
+    pub fn add_edge(&mut self, waiter: T, holder: T) {
+        if waiter != holder {
+            self.edges.entry(waiter).or_default().insert(holder);
+        }
+    }
+
+    pub fn remove(&mut self, node: &T) {
+        self.edges.remove(node);
+        for targets in self.edges.values_mut() {
+            targets.remove(node);
+        }
+    }
+
+    /// Some cycle in the graph, if there is one.
+    pub fn find_cycle(&self) -> Option<Vec<T>> {
+        let mut done = BTreeSet::new();
+        for start in self.edges.keys() {
+            if done.contains(start) {
+                continue;
+            }
+
+            let mut path = vec![start.clone()];
+            let mut successors = vec![self.successors(start)];
+            while let Some(next) = successors.last_mut().map(Iterator::next) {
+                match next {
+                    Some(next) => {
+                        if let Some(position) = path.iter().position(|node| node == next) {
+                            return Some(path.split_off(position));
+                        }
+                        if !done.contains(next) {
+                            path.push(next.clone());
+                            successors.push(self.successors(next));
+                        }
+                    }
+                    None => {
+                        done.insert(path.pop().unwrap());
+                        successors.pop();
+                    }
+                }
+            }
+        }
+        None
+    }
+
+    /// Breaks every cycle by repeatedly removing a victim chosen by `policy`, and returns the
+    /// victims in the order they were picked.
+    pub fn victims(mut self, policy: VictimPolicy, locks_held: impl Fn(&T) -> usize) -> Vec<T> {
+        let mut victims = Vec::new();
+        while let Some(cycle) = self.find_cycle() {
+            let victim = match policy {
+                VictimPolicy::Youngest => cycle.into_iter().max(),
+                VictimPolicy::FewestLocks => cycle
+                    .into_iter()
+                    .min_by_key(|node| (locks_held(node), Reverse(node.clone()))),
+            }
+            .unwrap();
+            self.remove(&victim);
+            victims.push(victim);
+        }
+        victims
+    }
+
+    pub fn edges(&self) -> impl Iterator<Item = (&T, &T)> {
+        self.edges
+            .iter()
+            .flat_map(|(waiter, holders)| holders.iter().map(move |holder| (waiter, holder)))
+    }
+
+    fn successors(&self, node: &T) -> impl Iterator<Item = &T> {
+        self.edges.get(node).into_iter().flatten()
+    }
+}
+
+/// The waits-for edges one shard currently sees, as shipped to the [`DeadlockCoordinator`].
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct WaitsForReport<T> {
+    /// Grows with every report from the same shard, so a late delivery never replaces a newer one.
+    pub seq: u64,
+    pub edges: Vec<(T, T)>,
+    pub locks_held: Vec<(T, usize)>,
+}
+
+/// What the deadlock coordinator reacts to.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum CoordinatorInput<T> {
+    Report(u32, WaitsForReport<T>),
+    /// Periodic trigger to look for cycles in the merged graph.
+    Round(VictimPolicy),
+}
+
+/// Finds deadlocks that span shards by merging the latest waits-for report of every shard.
+///
+/// The reports of different shards are taken at different times, so an edge one shard already
+/// dropped can join the fresh edges of another into a cycle that is gone. A round therefore only
+/// suspects the victims it picks, and aborts them once a later round picks them again from reports
+/// that every shard sent after the suspicion.
+///
+/// Victims are remembered until no report mentions them, so a shard that has not applied an abort
+/// yet cannot make a later round pick a second victim out of the same cycle.
+#[derive(Clone, Debug)]
+pub struct DeadlockCoordinator<T> {
+    reports: BTreeMap<u32, WaitsForReport<T>>,
+    /// The number of rounds before the latest report of each shard arrived.
+    arrived: BTreeMap<u32, u64>,
+    rounds: u64,
+    /// Victims waiting to be picked again, with the number of rounds every shard's report has to
+    /// have arrived after.
+    suspects: BTreeMap<T, u64>,
+    aborted: BTreeSet<T>,
+    /// Victims picked by the most recent batch.
+    pub victims: Vec<T>,
+}
+
+impl<T> Default for DeadlockCoordinator<T> {
+    fn default() -> Self {
+        Self {
+            reports: BTreeMap::new(),
+            arrived: BTreeMap::new(),
+            rounds: 0,
+            suspects: BTreeMap::new(),
+            aborted: BTreeSet::new(),
+            victims: Vec::new(),
+        }
+    }
+}
+
+impl<T: Clone + Ord> DeadlockCoordinator<T> {
+    pub fn apply_batch(&mut self, batch: Vec<CoordinatorInput<T>>) {
+        self.victims.clear();
+        for input in batch {
+            match input {
+                CoordinatorInput::Report(shard, report) => self.report(shard, report),
+                CoordinatorInput::Round(policy) => {
+                    let victims = self.round(policy);
+                    self.victims.extend(victims);
+                }
+            }
+        }
+    }
+
+    pub fn report(&mut self, shard: u32, report: WaitsForReport<T>) {
+        match self.reports.get(&shard) {
+            Some(latest) if latest.seq >= report.seq => {}
+            _ => {
+                self.reports.insert(shard, report);
+                self.arrived.insert(shard, self.rounds);
+            }
+        }
+    }
+
+    /// Picks victims out of every cycle in the merged graph, and returns the ones that were
+    /// already suspected, see [`DeadlockCoordinator`].
+    pub fn round(&mut self, policy: VictimPolicy) -> Vec<T> {
+        self.rounds += 1;
+        let reports = &self.reports;
+        self.aborted.retain(|key| {
+            reports.values().any(|report| {
+                report
+                    .edges
+                    .iter()
+                    .any(|(waiter, holder)| waiter == key || holder == key)
+                    || report.locks_held.iter().any(|(holder, _)| holder == key)
+            })
+        });
+
+        let mut graph = WaitsForGraph::default();
+        let mut locks_held = BTreeMap::<T, usize>::new();
+        for report in self.reports.values() {
+            for (waiter, holder) in &report.edges {
+                if !self.aborted.contains(waiter) && !self.aborted.contains(holder) {
+                    graph.add_edge(waiter.clone(), holder.clone());
+                }
+            }
+            for (holder, count) in &report.locks_held {
+                *locks_held.entry(holder.clone()).or_default() += count;
+            }
+        }
+
+        let oldest_report = self.arrived.values().min().copied().unwrap_or(0);
+        let mut confirmed = Vec::new();
+        let mut suspects = BTreeMap::new();
+        for victim in graph.victims(policy, |node| locks_held.get(node).copied().unwrap_or(0)) {
+            match self.suspects.get(&victim) {
+                Some(since) if oldest_report >= *since => confirmed.push(victim),
+                Some(since) => {
+                    suspects.insert(victim, *since);
+                }
+                None => {
+                    suspects.insert(victim, self.rounds);
+                }
+            }
+        }
+        self.suspects = suspects;
+        self.aborted.extend(confirmed.iter().cloned());
+        confirmed
+    }
+}
+
+#[stageleft::runtime]
+#[cfg(test)]
+mod tests {
+    use super::{DeadlockCoordinator, Prevention, VictimPolicy, WaitsForGraph, WaitsForReport};
+    use crate::lock_mode::LockMode;
+    use crate::lock_table::LockTable;
+
+    #[test]
+    fn finds_cycle_across_queues() {
+        let mut table = LockTable::default();
+        table.acquire("foo", 1, LockMode::X);
+        table.acquire("bar", 2, LockMode::X);
+        table.acquire("foo", 2, LockMode::S);
+        table.acquire("baz", 3, LockMode::S);
+
+        let graph = WaitsForGraph::from_queues(table.queues());
+        assert_eq!(graph.find_cycle(), None);
+
+        table.acquire("bar", 1, LockMode::S);
+        let mut cycle = WaitsForGraph::from_queues(table.queues())
+            .find_cycle()
+            .unwrap();
+        cycle.sort();
+        assert_eq!(cycle, vec![1, 2]);
+    }
+
+    #[test]
+    fn picks_victim_by_policy() {
+        let mut graph = WaitsForGraph::default();
+        graph.add_edge(1, 2);
+        graph.add_edge(2, 3);
+        graph.add_edge(3, 1);
+
+        assert_eq!(
+            graph.clone().victims(VictimPolicy::Youngest, |_| 0),
+            vec![3]
+        );
+        let locks_held = |node: &i32| if *node == 2 { 1 } else { 5 };
+        assert_eq!(
+            graph.victims(VictimPolicy::FewestLocks, locks_held),
+            vec![2]
+        );
+    }
+
+    #[test]
+    fn prevention_aborts_by_age() {
+        assert_eq!(Prevention::WaitDie.victim(1, 2), None);
+        assert_eq!(Prevention::WaitDie.victim(2, 1), Some(2));
+        assert_eq!(Prevention::WoundWait.victim(1, 2), Some(2));
+        assert_eq!(Prevention::WoundWait.victim(2, 1), None);
+    }
+
+    #[test]
+    fn coordinator_finds_cycle_across_shards() {
+        let report = |seq, edges| WaitsForReport {
+            seq,
+            edges,
+            locks_held: vec![],
+        };
+
+        let mut coordinator = DeadlockCoordinator::default();
+        coordinator.report(0, report(1, vec![(2, 1)]));
+        assert_eq!(coordinator.round(VictimPolicy::Youngest), vec![]);
+
+        coordinator.report(1, report(1, vec![(1, 2)]));
+        // a stale report from shard 0 arriving late does not hide its newer edges
+        coordinator.report(0, report(0, vec![]));
+        assert_eq!(coordinator.round(VictimPolicy::Youngest), vec![]);
+        // the cycle is only confirmed once both shards report it again
+        coordinator.report(0, report(2, vec![(2, 1)]));
+        assert_eq!(coordinator.round(VictimPolicy::Youngest), vec![]);
+        coordinator.report(1, report(2, vec![(1, 2)]));
+        assert_eq!(coordinator.round(VictimPolicy::Youngest), vec![2]);
+
+        // shard 1 has not applied the abort yet, but the victim is not picked twice
+        coordinator.report(0, report(3, vec![]));
+        assert_eq!(coordinator.round(VictimPolicy::Youngest), vec![]);
+
+        // and is forgotten once no shard mentions it
+        coordinator.report(1, report(3, vec![]));
+        coordinator.round(VictimPolicy::Youngest);
+        assert!(coordinator.aborted.is_empty());
+    }
+
+    #[test]
+    fn coordinator_ignores_cycle_gone_before_it_is_confirmed() {
+        let report = |seq, edges| WaitsForReport {
+            seq,
+            edges,
+            locks_held: vec![],
+        };
+
+        let mut coordinator = DeadlockCoordinator::default();
+        coordinator.report(0, report(1, vec![(2, 1)]));
+        coordinator.report(1, report(1, vec![(1, 2)]));
+        assert_eq!(coordinator.round(VictimPolicy::Youngest), vec![]);
+
+        // the wait on shard 0 timed out, and shard 1 still reports its side of the old cycle
+        coordinator.report(0, report(2, vec![]));
+        assert_eq!(coordinator.round(VictimPolicy::Youngest), vec![]);
+        coordinator.report(1, report(2, vec![(1, 2)]));
+        assert_eq!(coordinator.round(VictimPolicy::Youngest), vec![]);
+        assert!(coordinator.suspects.is_empty());
+    }
+}
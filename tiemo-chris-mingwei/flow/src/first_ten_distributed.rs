@@ -1,130 +1,1304 @@
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
 use hydroflow_plus::*;
+use serde::{Deserialize, Serialize};
 use stageleft::*;
 
-#[derive(Clone, Copy, Debug, PartialEq, PartialOrd, Eq, Ord, Hash)]
-pub enum LockMode {
-    NL,
-    IS,
-    IX,
-    S,
-    SIX,
-    X,
-}
-impl LockMode {
-    pub fn compatible(self, other: Self) -> bool {
-        match (self, other) {
-            (LockMode::NL, LockMode::NL) => true,
-            (LockMode::NL, LockMode::IS) => true,
-            (LockMode::NL, LockMode::IX) => true,
-            (LockMode::NL, LockMode::S) => true,
-            (LockMode::NL, LockMode::SIX) => true,
-            (LockMode::NL, LockMode::X) => true,
-            (LockMode::IS, LockMode::NL) => false,
-            (LockMode::IS, LockMode::IS) => true,
-            (LockMode::IS, LockMode::IX) => true,
-            (LockMode::IS, LockMode::S) => true,
-            (LockMode::IS, LockMode::SIX) => true,
-            (LockMode::IS, LockMode::X) => false,
-            (LockMode::IX, LockMode::NL) => true,
-            (LockMode::IX, LockMode::IS) => true,
-            (LockMode::IX, LockMode::IX) => true,
-            (LockMode::IX, LockMode::S) => false,
-            (LockMode::IX, LockMode::SIX) => false,
-            (LockMode::IX, LockMode::X) => false,
-            (LockMode::S, LockMode::NL) => true,
-            (LockMode::S, LockMode::IS) => true,
-            (LockMode::S, LockMode::IX) => false,
-            (LockMode::S, LockMode::S) => true,
-            (LockMode::S, LockMode::SIX) => false,
-            (LockMode::S, LockMode::X) => false,
-            (LockMode::SIX, LockMode::NL) => true,
-            (LockMode::SIX, LockMode::IS) => true,
-            (LockMode::SIX, LockMode::IX) => false,
-            (LockMode::SIX, LockMode::S) => false,
-            (LockMode::SIX, LockMode::SIX) => false,
-            (LockMode::SIX, LockMode::X) => false,
-            (LockMode::X, LockMode::NL) => true,
-            (LockMode::X, LockMode::IS) => false,
-            (LockMode::X, LockMode::IX) => false,
-            (LockMode::X, LockMode::S) => false,
-            (LockMode::X, LockMode::SIX) => false,
-            (LockMode::X, LockMode::X) => false,
-        }
-    }
-}
-
-#[derive(Clone, Copy, Debug, PartialEq, PartialOrd, Eq, Ord, Hash)]
+use crate::deadlock::{
+    CoordinatorInput, DeadlockCoordinator, Prevention, VictimPolicy, WaitsForGraph, WaitsForReport,
+};
+use crate::heartbeats::failure_detector;
+use crate::hierarchy::{EntityPath, HierarchicalLockTable, Resumed};
+use crate::lock_client::{ClientPorts, LockClient};
+use crate::lock_mode::LockMode;
+use crate::lock_table::{AcquireOutcome, Fairness, TableUsage};
+use crate::wal::WriteAheadLog;
+
+/// A request for `requested_state` on a lock, from the client named `client_id`.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct LockRequest {
-    client_id: &'static str,
-    requested_state: LockMode,
+    pub client_id: String,
+    pub requested_state: LockMode,
 }
 
-#[derive(Clone, Copy, Debug, PartialOrd, Ord, PartialEq, Eq, Hash)]
+#[derive(Clone, Copy, Debug, PartialOrd, Ord, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct EntityId(pub usize);
 
-#[derive(Clone, Copy, Debug, PartialOrd, Ord, PartialEq, Eq, Hash)]
+#[derive(Clone, Copy, Debug, PartialOrd, Ord, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct TransactionId(pub usize);
 
-#[derive(Clone, Copy, Debug, PartialOrd, Ord, PartialEq, Eq, Hash)]
+#[derive(Clone, Copy, Debug, PartialOrd, Ord, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct MachineId(pub usize);
 // Key: (TransactionId, MachineId)
-#[derive(Clone, Copy, Debug, PartialOrd, Ord, PartialEq, Eq, Hash)]
+#[derive(Clone, Copy, Debug, PartialOrd, Ord, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct Key {
-    transaction_id: TransactionId,
-    machine_id: MachineId,
+    pub transaction_id: TransactionId,
+    pub machine_id: MachineId,
 }
 
-// Client entry point: source of transaction commands (transaction id, command type)
-/*
-begin txn: (0, begin_txn)
-acquire: (transaction id, acquire(entity id, mode))
-release: (transaction id, release(entity id))
-commit txn: (transaction id, commit)
-abort txn: (transaction id, abort)
-*/
+/// Hands out transaction ids from a hybrid logical clock per machine: the wall-clock time in
+/// milliseconds, shifted left by [`TransactionIds::LOGICAL_BITS`], plus a logical counter that
+/// keeps one machine's ids strictly increasing even if its clock stalls or goes backwards. Paired
+/// with the machine id in a [`Key`], ids are unique, and comparing them compares start times up to
+/// clock skew, so older transactions order first.
+#[derive(Clone, Debug, Default)]
+pub struct TransactionIds {
+    last: BTreeMap<MachineId, TransactionId>,
+    /// Keys handed out by the most recent batch.
+    pub issued: Vec<Key>,
+}
 
-// client to socket mapping ()
+impl TransactionIds {
+    pub const LOGICAL_BITS: u32 = 16;
 
+    /// Starts a transaction for each machine in `batch`, in order. `now` is the current time since
+    /// the UNIX epoch.
+    pub fn apply_batch(&mut self, now: Duration, batch: Vec<MachineId>) {
+        self.issued.clear();
+        let physical = (now.as_millis() as usize) << Self::LOGICAL_BITS;
+        for machine_id in batch {
+            let last = self.last.entry(machine_id).or_insert(TransactionId(0));
+            *last = TransactionId(physical.max(last.0 + 1));
+            self.issued.push(Key {
+                transaction_id: *last,
+                machine_id,
+            });
+        }
+    }
+}
+
+/// A request a transaction sends to the lock service. Transactions are started at the client, which
+/// hands out their keys; see [`LockClient::begin`].
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub enum ClientRequest {
-    BeginTransaction,
+    /// Waits for the lock as long as the service's default timeout allows.
     Acquire {
-        entity_id: EntityId,
+        entity: EntityPath<EntityId>,
+        mode: LockMode,
+    },
+    /// Takes the lock only if it can be granted right away, and is denied otherwise.
+    TryAcquire {
+        entity: EntityPath<EntityId>,
         mode: LockMode,
     },
+    /// Waits for the lock for at most `timeout`, instead of the service's default.
+    AcquireWithin {
+        entity: EntityPath<EntityId>,
+        mode: LockMode,
+        timeout: Duration,
+    },
     Release {
-        entity_id: usize,
+        entity: EntityPath<EntityId>,
     },
     Commit,
     Abort,
+    /// Keeps the transaction's lease from running out without changing its locks.
+    RenewLease,
+    /// Asks for the state of the queue of `entity`, or of every queue with `None`, which is
+    /// answered with a [`LockResponse::Snapshot`].
+    Inspect {
+        entity: Option<EntityPath<EntityId>>,
+    },
+}
+
+impl ClientRequest {
+    /// The entity an acquire, release or inspection is for.
+    pub fn entity(&self) -> Option<&EntityPath<EntityId>> {
+        match self {
+            ClientRequest::Acquire { entity, .. }
+            | ClientRequest::TryAcquire { entity, .. }
+            | ClientRequest::AcquireWithin { entity, .. }
+            | ClientRequest::Release { entity } => Some(entity),
+            ClientRequest::Inspect { entity } => entity.as_ref(),
+            ClientRequest::Commit | ClientRequest::Abort | ClientRequest::RenewLease => None,
+        }
+    }
+
+    pub fn is_acquire(&self) -> bool {
+        matches!(
+            self,
+            ClientRequest::Acquire { .. }
+                | ClientRequest::TryAcquire { .. }
+                | ClientRequest::AcquireWithin { .. }
+        )
+    }
+}
+
+/// What the lock service sends back to the client that issued a request. An acquire that has to
+/// wait is answered with `Queued` first, and later with one of `Granted`, `Denied` or `TimedOut`
+/// unless its transaction is aborted in the meantime; a try-acquire is denied instead. Commits,
+/// aborts and lease renewals go to every shard, so each shard answers them.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum LockResponse {
+    /// A new transaction was started, under this key.
+    Began {
+        key: Key,
+    },
+    /// The lock is held; `mode` is what the transaction now holds on the entity, which is the
+    /// join of the requested mode and any lock it already had there.
+    Granted {
+        key: Key,
+        entity: EntityPath<EntityId>,
+        mode: LockMode,
+    },
+    /// The acquire is waiting for incompatible holders to go away.
+    Queued {
+        key: Key,
+        entity: EntityPath<EntityId>,
+        mode: LockMode,
+    },
+    /// The request was refused, and the transaction can carry on without it.
+    Denied {
+        key: Key,
+        request: ClientRequest,
+        reason: DenyReason,
+    },
+    Released {
+        key: Key,
+        entity: EntityPath<EntityId>,
+    },
+    /// The acquire waited longer than its timeout and was withdrawn from the queue.
+    TimedOut {
+        key: Key,
+        entity: EntityPath<EntityId>,
+        mode: LockMode,
+    },
+    LeaseRenewed {
+        key: Key,
+    },
+    /// The transaction is over and its locks are released, as one shard sees it.
+    Committed {
+        key: Key,
+        /// The shard that committed it.
+        shard: usize,
+        /// How many shards answer the commit, which goes to every one of them.
+        shards: usize,
+    },
+    /// The transaction is over and its locks are released, either on request or because the
+    /// service gave up on it.
+    Aborted {
+        key: Key,
+        cause: AbortCause,
+    },
+    /// The state of the queues asked for by an inspection of `entity`, ordered by entity, as one
+    /// shard sees them.
+    Snapshot {
+        key: Key,
+        entity: Option<EntityPath<EntityId>>,
+        /// The shard that took the snapshot.
+        shard: usize,
+        /// How many shards answer the inspection, each with a snapshot of its own: every shard for
+        /// the whole table, and only the owner for one entity.
+        shards: usize,
+        queues: Vec<QueueSnapshot>,
+        /// How much the shard's whole table takes up.
+        usage: TableUsage,
+    },
+    /// The request could not be carried out where it was sent, and had no effect.
+    Error {
+        key: Key,
+        request: ClientRequest,
+        error: LockError,
+    },
 }
 
-fn process_client_requests<'a, D: Deploy<'a>>(
-    begin_transaction_reqs: Stream<'a, MachineId, stream::Windowed, D::Process>,
-    acquire_reqs: Stream<'a, (Key, EntityId, LockMode), stream::Windowed, D::Process>,
-    release_reqs: Stream<'a, (Key, EntityId), stream::Windowed, D::Process>,
-    commit_reqs: Stream<'a, Key, stream::Windowed, D::Process>,
-    abort_reqs: Stream<'a, Key, stream::Windowed, D::Process>,
-    // cluster: D::Cluster,
-) -> Stream<'a, i32, stream::Async, D::Cluster> {
+impl LockResponse {
+    pub fn key(&self) -> Key {
+        match self {
+            LockResponse::Began { key }
+            | LockResponse::Granted { key, .. }
+            | LockResponse::Queued { key, .. }
+            | LockResponse::Denied { key, .. }
+            | LockResponse::Released { key, .. }
+            | LockResponse::TimedOut { key, .. }
+            | LockResponse::LeaseRenewed { key }
+            | LockResponse::Committed { key, .. }
+            | LockResponse::Aborted { key, .. }
+            | LockResponse::Snapshot { key, .. }
+            | LockResponse::Error { key, .. } => *key,
+        }
+    }
+}
+
+/// The state of one lock queue at the time it was inspected.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct QueueSnapshot {
+    pub entity: EntityPath<EntityId>,
+    pub group_mode: LockMode,
+    pub holders: Vec<(Key, LockMode)>,
+    /// Holders waiting to convert their grant, with the mode they convert to.
+    pub conversions: Vec<WaiterSnapshot>,
+    /// Queued requests, in the order they are going to be served.
+    pub waiters: Vec<WaiterSnapshot>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct WaiterSnapshot {
+    pub key: Key,
+    pub mode: LockMode,
+    /// How long the request has waited as of the latest expiry tick, or `None` if no expiry tick
+    /// has happened since it was queued.
+    pub waited: Option<Duration>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum DenyReason {
+    /// A [`ClientRequest::TryAcquire`] could not be granted without waiting.
+    WouldWait,
+    /// The transaction already has an acquire waiting on this entity.
+    AlreadyWaiting,
+    /// The upgrade would deadlock against another pending upgrade of the same lock.
+    ConversionDeadlock,
+    /// The transaction released a lock before, so two-phase locking forbids new ones.
+    Shrinking,
+    /// The lock table is full: granting or queueing the acquire would make the member track more
+    /// entities than [`LockConfig::max_entities`].
+    Backpressure,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum AbortCause {
+    /// The client sent [`ClientRequest::Abort`].
+    Requested,
+    /// The transaction was picked as the victim of a deadlock cycle.
+    Deadlock,
+    /// The configured [`Prevention`] scheme forbade it to wait.
+    Prevention,
+    LeaseExpired,
+    MachineFailed,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum LockError {
+    /// The entity belongs to another shard.
+    WrongShard,
+    /// The transaction does not hold the lock it tried to release.
+    NotHeld,
+    /// The transaction has no locks here to renew the lease of.
+    NoLocks,
+    /// The request could not be written to the member's log, so it was not applied.
+    Unlogged,
+}
+
+/// Everything a lock service member reacts to.
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum LockInput {
+    /// A request from the client with this member id, which gets the responses.
+    Request(u32, Key, ClientRequest),
+    /// Periodic trigger to look for deadlocks among the waiters and abort victims.
+    DetectDeadlocks(VictimPolicy),
+    /// Periodic trigger to snapshot the local waits-for graph for the deadlock coordinator.
+    ReportWaitsFor,
+    /// A victim picked by the deadlock coordinator. Only members the transaction had locks or
+    /// waiters on abort it, so members that never saw it stay silent.
+    AbortVictim(Key),
+    /// Periodic trigger to withdraw acquires that waited too long and abort transactions whose
+    /// lease ran out, carrying the current time since the UNIX epoch so that applying a batch never
+    /// reads the clock itself.
+    Expire(Duration),
+    /// The failure detector considers this machine dead, so every transaction it runs is aborted.
+    MachineFailed(MachineId),
+}
+
+/// How a lock service member behaves, chosen when the flow is built.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct LockConfig {
+    /// Aborts transactions before they can deadlock, instead of waiting for detection.
+    pub prevention: Option<Prevention>,
+    /// How long an acquire waits if it does not ask for its own timeout. `None` waits forever.
+    pub default_timeout: Option<Duration>,
+    /// How long a transaction keeps its locks without sending a request. `None` never expires.
+    pub lease: Option<Duration>,
+    /// How many locks a transaction may hold on the children of one entity before they are swapped
+    /// for a single lock on that entity. `None` never escalates.
+    pub escalation_threshold: Option<usize>,
+    /// The order in which queued acquires are granted.
+    pub fairness: Fairness,
+    /// How many entities each member keeps a queue for. Acquires that would need more are denied
+    /// with [`DenyReason::Backpressure`] until locks are released. `None` is unbounded.
+    pub max_entities: Option<usize>,
+}
 
-    acquire_reqs
+/// A queued acquire, which gives up after `timeout` if it has one. Its wait is timed from the first
+/// expiry tick after it was queued, so it waits at least `timeout` and at most one tick longer.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct PendingWait {
+    mode: LockMode,
+    timeout: Option<Duration>,
+    /// Whether the timeout came with the request rather than from the service's default.
+    requested: bool,
+    since: Option<Duration>,
 }
 
+/// A record in the write-ahead log of a lock service member.
+#[derive(Debug, Serialize, Deserialize)]
+enum LogRecord {
+    Batch(Vec<LockInput>),
+    /// The whole state of the member, which stands in for every record before it.
+    Snapshot(Box<LockManager>),
+}
+
+/// The state of a lock service member, folded over every batch of inputs it has received.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct LockManager {
+    pub table: HierarchicalLockTable<EntityId, Key>,
+    config: LockConfig,
+    /// This member's shard and the number of shards. Unsharded managers own every entity.
+    shard: Option<(usize, usize)>,
+    waits: BTreeMap<(Key, EntityPath<EntityId>), PendingWait>,
+    /// The time of the latest expiry tick, which is as close as the manager gets to reading a
+    /// clock.
+    now: Option<Duration>,
+    /// When each transaction with a lease loses its locks. Like wait deadlines, a lease is set
+    /// running by the first expiry tick after the transaction's latest request.
+    leases: BTreeMap<Key, Option<Duration>>,
+    /// Transactions that released a lock and so, under two-phase locking, may not acquire any more
+    /// until they end. Each shard only knows about the releases it handled itself; the
+    /// [`LockClient`] keeps transactions from acquiring on one shard after releasing on another.
+    shrinking: BTreeSet<Key>,
+    /// The client each transaction's responses go to, as of its latest request, for as long as it
+    /// holds or waits for something here.
+    reply_to: BTreeMap<Key, u32>,
+    report_seq: u64,
+    /// Responses produced by the most recent batch, each with the client it goes to.
+    #[serde(skip)]
+    pub outbox: Vec<(u32, LockResponse)>,
+    /// The waits-for snapshot taken by the most recent batch, if it asked for one.
+    #[serde(skip)]
+    pub report: Option<WaitsForReport<Key>>,
+    /// Where every batch is written before it is applied, if the manager should survive restarts.
+    #[serde(skip)]
+    log: Option<WriteAheadLog<LogRecord>>,
+    /// The size the log may grow to before it is replaced by a snapshot.
+    #[serde(skip)]
+    compact_at: u64,
+    /// Inputs of a batch that could not be logged which only arrive once, so they are applied with
+    /// the next batch instead.
+    #[serde(skip)]
+    unlogged: Vec<LockInput>,
+}
+
+impl LockManager {
+    /// The smallest log that is worth replacing by a snapshot.
+    const MIN_COMPACTION_SIZE: u64 = 1 << 20;
+
+    pub fn new(config: LockConfig) -> Self {
+        Self {
+            table: HierarchicalLockTable::new(config.fairness, config.escalation_threshold),
+            config,
+            ..Default::default()
+        }
+    }
+
+    /// A manager for shard `shard` out of `num_shards`, which refuses requests for entities owned
+    /// by another shard.
+    pub fn new_shard(config: LockConfig, shard: usize, num_shards: usize) -> Self {
+        Self {
+            shard: Some((shard, num_shards)),
+            ..Self::new(config)
+        }
+    }
+
+    /// Replays the write-ahead log at `path` to rebuild the holders and waiters from before a
+    /// restart, and from then on logs every batch there before applying it. Responses to the
+    /// replayed batches are not sent again.
+    ///
+    /// Batches that change nothing but the time of the latest tick are not logged, and once the
+    /// log has grown to twice its size after the last snapshot, it is replaced by a new one.
+    ///
+    /// A snapshot carries the config and shard of the member that took it, and replaying one taken
+    /// by a member set up differently than this one fails with [`io::ErrorKind::InvalidData`].
+    pub fn recover(mut self, path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref();
+        let (log, records) = WriteAheadLog::open(path)?;
+        for record in records {
+            match record {
+                LogRecord::Batch(batch) => self.apply_batch(batch),
+                LogRecord::Snapshot(manager) => {
+                    if (manager.config, manager.shard) != (self.config, self.shard) {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            format!(
+                                "the lock log at {} was written with {:?} for shard {:?}",
+                                path.display(),
+                                manager.config,
+                                manager.shard
+                            ),
+                        ));
+                    }
+                    self = *manager;
+                }
+            }
+        }
+        self.outbox.clear();
+        self.report = None;
+        // reports were numbered by batches that never made it to the log too, and the coordinator
+        // ignores any report numbered below one it has seen, so count on from the clock instead
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        self.report_seq = self.report_seq.max(now.as_micros() as u64);
+        self.compact_at = Self::MIN_COMPACTION_SIZE.max(2 * log.size()?);
+        self.log = Some(log);
+        Ok(self)
+    }
+
+    /// Whether `batch` changes nothing that has to survive a restart: it only takes waits-for
+    /// snapshots and runs expiry ticks with no wait or lease to start timing or to expire.
+    fn changes_nothing(&self, batch: &[LockInput]) -> bool {
+        batch.iter().all(|input| match input {
+            LockInput::ReportWaitsFor => true,
+            LockInput::Expire(now) => {
+                let waits_expire = self.waits.values().any(|wait| match wait.since {
+                    Some(since) => wait.timeout.is_some_and(|timeout| since + timeout <= *now),
+                    None => true,
+                });
+                let leases_expire = self.config.lease.is_some()
+                    && self.leases.values().any(|deadline| match deadline {
+                        Some(deadline) => deadline <= now,
+                        None => true,
+                    });
+                !waits_expire && !leases_expire
+            }
+            _ => false,
+        })
+    }
+
+    /// Answers the requests of a batch that could not be logged with [`LockError::Unlogged`],
+    /// since applying them would put the state ahead of the log. Victims and failed machines are
+    /// kept for the next batch; the periodic inputs are dropped, as the next tick repeats them.
+    fn refuse_unlogged(&mut self, batch: Vec<LockInput>, error: io::Error) {
+        eprintln!("failed to write the lock log: {}", error);
+        for input in batch {
+            match input {
+                LockInput::Request(client, key, request) => self.outbox.push((
+                    client,
+                    LockResponse::Error {
+                        key,
+                        request,
+                        error: LockError::Unlogged,
+                    },
+                )),
+                LockInput::AbortVictim(_) | LockInput::MachineFailed(_) => {
+                    self.unlogged.push(input)
+                }
+                _ => {}
+            }
+        }
+    }
+
+    /// Replaces the log by a snapshot of the state if it has grown past `compact_at`.
+    fn compact_if_due(&mut self) {
+        let Some(log) = self.log.take() else {
+            return;
+        };
+        match log.size() {
+            Ok(size) if size >= self.compact_at => {}
+            _ => {
+                self.log = Some(log);
+                return;
+            }
+        }
+
+        let record = LogRecord::Snapshot(Box::new(std::mem::take(self)));
+        let written = log.rewrite(&record);
+        let LogRecord::Snapshot(manager) = record else {
+            unreachable!()
+        };
+        *self = *manager;
+        if let Err(error) = written {
+            // the old log is still whole, so this is only tried again once it doubles
+            eprintln!("failed to compact the lock log: {}", error);
+        }
+        self.compact_at = Self::MIN_COMPACTION_SIZE.max(2 * log.size().unwrap_or(self.compact_at));
+        self.log = Some(log);
+    }
+
+    fn owns(&self, entity: &EntityPath<EntityId>) -> bool {
+        match self.shard {
+            Some((shard, num_shards)) => shard_of(entity, num_shards) == shard,
+            None => true,
+        }
+    }
+
+    pub fn apply_batch(&mut self, mut batch: Vec<LockInput>) {
+        self.outbox.clear();
+        self.report = None;
+        batch.splice(0..0, std::mem::take(&mut self.unlogged));
+
+        let logged = match self.log.clone() {
+            Some(log) if !self.changes_nothing(&batch) => {
+                let record = LogRecord::Batch(batch);
+                let written = log.append(&record);
+                let LogRecord::Batch(unwrapped) = record else {
+                    unreachable!()
+                };
+                batch = unwrapped;
+                if let Err(error) = written {
+                    self.refuse_unlogged(batch, error);
+                    return;
+                }
+                true
+            }
+            _ => false,
+        };
+
+        for input in batch {
+            match input {
+                LockInput::Request(client, key, request) => {
+                    self.reply_to.insert(key, client);
+                    self.apply(key, request);
+                    self.renew_lease(key);
+                    self.prevent_deadlocks();
+                    self.forget_if_idle(key);
+                }
+                LockInput::DetectDeadlocks(policy) => self.detect_deadlocks(policy),
+                LockInput::ReportWaitsFor => self.report = Some(self.waits_for_report()),
+                LockInput::AbortVictim(key) => {
+                    if self.table.table().involves(&key) {
+                        self.abort(key, AbortCause::Deadlock);
+                    }
+                }
+                LockInput::Expire(now) => {
+                    self.now = Some(now);
+                    self.expire_waits(now);
+                    self.expire_leases(now);
+                }
+                LockInput::MachineFailed(machine) => {
+                    let keys = self
+                        .table
+                        .table()
+                        .involved()
+                        .filter(|key| key.machine_id == machine)
+                        .copied()
+                        .collect::<BTreeSet<_>>();
+                    for key in keys {
+                        self.abort(key, AbortCause::MachineFailed);
+                    }
+                }
+            }
+        }
+        if logged {
+            self.compact_if_due();
+        }
+    }
+
+    fn renew_lease(&mut self, key: Key) {
+        if self.config.lease.is_some() && self.table.table().involves(&key) {
+            self.leases.insert(key, None);
+        } else {
+            self.leases.remove(&key);
+        }
+    }
+
+    fn expire_leases(&mut self, now: Duration) {
+        let Some(lease) = self.config.lease else {
+            return;
+        };
+        let expired = self
+            .leases
+            .iter_mut()
+            .filter_map(|(key, deadline)| {
+                (*deadline.get_or_insert(now + lease) <= now).then_some(*key)
+            })
+            .collect::<Vec<_>>();
+        for key in expired {
+            self.abort(key, AbortCause::LeaseExpired);
+        }
+    }
+
+    fn expire_waits(&mut self, now: Duration) {
+        let expired = self
+            .waits
+            .iter_mut()
+            .filter_map(|(waiting, wait)| {
+                let since = *wait.since.get_or_insert(now);
+                let timeout = wait.timeout?;
+                (since + timeout <= now).then(|| waiting.clone())
+            })
+            .collect::<Vec<_>>();
+        for (key, entity) in expired {
+            // an earlier expiry in this loop may have let it through already
+            let Some(wait) = self.waits.remove(&(key, entity.clone())) else {
+                continue;
+            };
+            if let Some(resumed) = self.table.cancel(&entity, &key) {
+                self.respond(LockResponse::TimedOut {
+                    key,
+                    entity,
+                    mode: wait.mode,
+                });
+                self.forget_if_idle(key);
+                self.push_resumed(resumed);
+            }
+        }
+    }
+
+    /// Aborts transactions until no wait left in the table is forbidden by the prevention scheme.
+    /// Aborting one can let others through, which may then wait somewhere else. Only the queues
+    /// that changed since the last check are looked at, since the waits in the others were allowed
+    /// then and still are.
+    fn prevent_deadlocks(&mut self) {
+        let mut changed = self.table.take_changed();
+        let Some(prevention) = self.config.prevention else {
+            return;
+        };
+        loop {
+            let table = self.table.table();
+            let queues = changed
+                .iter()
+                .filter_map(|entity| Some((entity, table.queue(entity)?)));
+            let Some(victim) = WaitsForGraph::from_queues(queues)
+                .edges()
+                .find_map(|(waiter, blocker)| prevention.victim(*waiter, *blocker))
+            else {
+                return;
+            };
+            self.abort(victim, AbortCause::Prevention);
+            changed.extend(self.table.take_changed());
+        }
+    }
+
+    fn waits_for_report(&mut self) -> WaitsForReport<Key> {
+        let table = self.table.table();
+        let graph = WaitsForGraph::from_queues(table.queues());
+        let mut holders = table
+            .queues()
+            .flat_map(|(_, queue)| queue.holders().iter().map(|(holder, _)| *holder))
+            .collect::<Vec<_>>();
+        holders.sort();
+        holders.dedup();
+
+        self.report_seq += 1;
+        WaitsForReport {
+            seq: self.report_seq,
+            edges: graph
+                .edges()
+                .map(|(waiter, holder)| (*waiter, *holder))
+                .collect(),
+            locks_held: holders
+                .into_iter()
+                .map(|holder| (holder, table.locks_held(&holder)))
+                .collect(),
+        }
+    }
+
+    /// Aborts a victim out of every cycle in the waits-for graph, releasing its locks just as
+    /// [`ClientRequest::Abort`] would.
+    fn detect_deadlocks(&mut self, policy: VictimPolicy) {
+        let table = self.table.table();
+        let victims =
+            WaitsForGraph::from_queues(table.queues()).victims(policy, |key| table.locks_held(key));
+        for victim in victims {
+            self.abort(victim, AbortCause::Deadlock);
+        }
+    }
+
+    /// Whether locking `entity` would add more queues than the table may hold. Counts the
+    /// ancestors too, since the acquire takes intention locks on them.
+    fn over_capacity(&self, entity: &EntityPath<EntityId>) -> bool {
+        let Some(max_entities) = self.config.max_entities else {
+            return false;
+        };
+        let table = self.table.table();
+        let new = entity
+            .ancestors()
+            .chain([entity.clone()])
+            .filter(|entity| table.queue(entity).is_none())
+            .count();
+        new > 0 && table.entities() + new > max_entities
+    }
+
+    fn apply(&mut self, key: Key, request: ClientRequest) {
+        let wrong_shard = request.entity().is_some_and(|entity| !self.owns(entity));
+        if wrong_shard {
+            self.respond(LockResponse::Error {
+                key,
+                request,
+                error: LockError::WrongShard,
+            });
+            return;
+        }
+
+        match &request {
+            _ if request.is_acquire() && self.shrinking.contains(&key) => {
+                self.respond(LockResponse::Denied {
+                    key,
+                    request,
+                    reason: DenyReason::Shrinking,
+                });
+            }
+            ClientRequest::Acquire { entity, .. }
+            | ClientRequest::TryAcquire { entity, .. }
+            | ClientRequest::AcquireWithin { entity, .. }
+                if self.over_capacity(entity) =>
+            {
+                self.respond(LockResponse::Denied {
+                    key,
+                    request: request.clone(),
+                    reason: DenyReason::Backpressure,
+                });
+            }
+            ClientRequest::TryAcquire { entity, mode }
+                if !self.table.would_grant(entity, &key, *mode) =>
+            {
+                self.respond(LockResponse::Denied {
+                    key,
+                    request: request.clone(),
+                    reason: DenyReason::WouldWait,
+                });
+            }
+            ClientRequest::Acquire { entity, mode }
+            | ClientRequest::TryAcquire { entity, mode }
+            | ClientRequest::AcquireWithin { entity, mode, .. } => {
+                match self.table.acquire(entity.clone(), key, *mode) {
+                    AcquireOutcome::Granted => self.respond(LockResponse::Granted {
+                        key,
+                        entity: entity.clone(),
+                        mode: self.table.granted_mode(entity, &key, *mode),
+                    }),
+                    AcquireOutcome::Waiting => {
+                        let (timeout, requested) = match &request {
+                            ClientRequest::AcquireWithin { timeout, .. } => (Some(*timeout), true),
+                            _ => (self.config.default_timeout, false),
+                        };
+                        let wait = PendingWait {
+                            mode: *mode,
+                            timeout,
+                            requested,
+                            since: None,
+                        };
+                        self.waits.insert((key, entity.clone()), wait);
+                        self.respond(LockResponse::Queued {
+                            key,
+                            entity: entity.clone(),
+                            mode: *mode,
+                        });
+                    }
+                    outcome => self.respond(LockResponse::Denied {
+                        key,
+                        request: request.clone(),
+                        reason: DenyReason::from(outcome),
+                    }),
+                }
+            }
+            ClientRequest::Release { entity } => match self.table.release(entity, &key) {
+                Some(resumed) => {
+                    self.waits.remove(&(key, entity.clone()));
+                    self.shrinking.insert(key);
+                    self.respond(LockResponse::Released {
+                        key,
+                        entity: entity.clone(),
+                    });
+                    self.push_resumed(resumed);
+                }
+                None => self.respond(LockResponse::Error {
+                    key,
+                    request,
+                    error: LockError::NotHeld,
+                }),
+            },
+            ClientRequest::Commit => {
+                let resumed = self.end(key);
+                let (shard, shards) = self.shard.unwrap_or((0, 1));
+                self.respond(LockResponse::Committed { key, shard, shards });
+                self.reply_to.remove(&key);
+                self.push_resumed(resumed);
+            }
+            ClientRequest::Abort => self.abort(key, AbortCause::Requested),
+            ClientRequest::Inspect { entity } => {
+                let (shard, shards) = match (self.shard, entity) {
+                    (Some((shard, num_shards)), None) => (shard, num_shards),
+                    (Some((shard, _)), Some(_)) => (shard, 1),
+                    (None, _) => (0, 1),
+                };
+                let queues = self.inspect(entity.as_ref());
+                let usage = self.usage();
+                self.respond(LockResponse::Snapshot {
+                    key,
+                    entity: entity.clone(),
+                    shard,
+                    shards,
+                    queues,
+                    usage,
+                });
+            }
+            ClientRequest::RenewLease => {
+                if self.table.table().involves(&key) {
+                    self.respond(LockResponse::LeaseRenewed { key });
+                } else {
+                    self.respond(LockResponse::Error {
+                        key,
+                        request,
+                        error: LockError::NoLocks,
+                    });
+                }
+            }
+        }
+    }
+
+    /// What the lock table keeps track of right now, which [`LockConfig::max_entities`] bounds.
+    pub fn usage(&self) -> TableUsage {
+        self.table.table().usage()
+    }
+
+    /// The state of the queue of `entity`, or of every queue with `None`. Queues with no holders
+    /// and no waiters are left out.
+    pub fn inspect(&self, entity: Option<&EntityPath<EntityId>>) -> Vec<QueueSnapshot> {
+        let mut queues = self
+            .table
+            .table()
+            .queues()
+            .filter(|(queued, queue)| {
+                (entity.is_none() || entity == Some(*queued)) && !queue.is_empty()
+            })
+            .map(|(queued, queue)| QueueSnapshot {
+                entity: queued.clone(),
+                group_mode: queue.group_mode(),
+                holders: queue.holders().to_vec(),
+                conversions: queue
+                    .conversions()
+                    .iter()
+                    .map(|(key, mode)| self.waiter(queued, *key, *mode))
+                    .collect(),
+                waiters: queue
+                    .queued()
+                    .into_iter()
+                    .map(|(key, mode, _)| self.waiter(queued, *key, mode))
+                    .collect(),
+            })
+            .collect::<Vec<_>>();
+        queues.sort_by(|a, b| a.entity.cmp(&b.entity));
+        queues
+    }
+
+    fn waiter(&self, entity: &EntityPath<EntityId>, key: Key, mode: LockMode) -> WaiterSnapshot {
+        // the acquire may be for something beneath the entity, and wait on an intention lock here
+        let since = self
+            .waits
+            .range((key, EntityPath(Vec::new()))..)
+            .take_while(|((waiting, _), _)| *waiting == key)
+            .find(|((_, requested), _)| requested == entity || entity.is_ancestor_of(requested))
+            .and_then(|(_, wait)| wait.since);
+        WaiterSnapshot {
+            key,
+            mode,
+            waited: since.zip(self.now).map(|(since, now)| now - since),
+        }
+    }
+
+    fn abort(&mut self, key: Key, cause: AbortCause) {
+        let resumed = self.end(key);
+        self.respond(LockResponse::Aborted { key, cause });
+        self.reply_to.remove(&key);
+        self.push_resumed(resumed);
+    }
+
+    /// Releases everything the transaction holds or waits for, returning what that let through.
+    fn end(&mut self, key: Key) -> Vec<Resumed<EntityId, Key>> {
+        let resumed = self.table.release_all(&key);
+        // waits are ordered by key first, and no path comes before the empty one
+        let ended = self
+            .waits
+            .range((key, EntityPath(Vec::new()))..)
+            .take_while(|((waiting, _), _)| *waiting == key)
+            .map(|(waiting, _)| waiting.clone())
+            .collect::<Vec<_>>();
+        for waiting in ended {
+            self.waits.remove(&waiting);
+        }
+        self.leases.remove(&key);
+        self.shrinking.remove(&key);
+        resumed
+    }
+
+    /// Queues a response for the client that sent the transaction's latest request. Transactions
+    /// only show up here through their requests, so there always is one.
+    fn respond(&mut self, response: LockResponse) {
+        if let Some(client) = self.reply_to.get(&response.key()) {
+            self.outbox.push((*client, response));
+        }
+    }
+
+    /// Forgets the client of a transaction that has nothing left here, so that transactions which
+    /// only inspect, or give up after being refused, are not remembered forever. Its next request
+    /// says where to reply again.
+    fn forget_if_idle(&mut self, key: Key) {
+        if !self.table.table().involves(&key) {
+            self.reply_to.remove(&key);
+        }
+    }
+
+    fn push_resumed(&mut self, resumed: Vec<Resumed<EntityId, Key>>) {
+        for resumed in resumed {
+            let response = match resumed {
+                Resumed::Granted(entity, key, mode) => {
+                    self.waits.remove(&(key, entity.clone()));
+                    LockResponse::Granted { key, entity, mode }
+                }
+                Resumed::Denied(entity, key, mode, outcome) => {
+                    let request = match self.waits.remove(&(key, entity.clone())) {
+                        Some(PendingWait {
+                            timeout: Some(timeout),
+                            requested: true,
+                            ..
+                        }) => ClientRequest::AcquireWithin {
+                            entity,
+                            mode,
+                            timeout,
+                        },
+                        _ => ClientRequest::Acquire { entity, mode },
+                    };
+                    LockResponse::Denied {
+                        key,
+                        request,
+                        reason: DenyReason::from(outcome),
+                    }
+                }
+            };
+            let key = response.key();
+            self.respond(response);
+            self.forget_if_idle(key);
+        }
+    }
+}
+
+impl From<AcquireOutcome> for DenyReason {
+    /// Only meant for the outcomes that refuse a request.
+    fn from(outcome: AcquireOutcome) -> Self {
+        match outcome {
+            AcquireOutcome::AlreadyWaiting => DenyReason::AlreadyWaiting,
+            AcquireOutcome::ConversionDeadlock => DenyReason::ConversionDeadlock,
+            AcquireOutcome::Granted | AcquireOutcome::Waiting => {
+                unreachable!("{:?} is not a refusal", outcome)
+            }
+        }
+    }
+}
+
+/// The shard owning `entity`, by a hash of its root. A whole hierarchy lives on the shard of its
+/// root, so intention locks are always taken next to the locks below them.
+pub fn shard_of(entity: &EntityPath<EntityId>, num_shards: usize) -> usize {
+    // Fibonacci hashing spreads consecutive ids evenly, and unlike `DefaultHasher` it is guaranteed
+    // to agree between every client and shard
+    let root = entity.0.first().map_or(0, |root| root.0 as u64);
+    let hash = root.wrapping_mul(0x9E37_79B9_7F4A_7C15) >> 32;
+    ((hash * num_shards as u64) >> 32) as usize
+}
+
+/// Starts transactions without involving the lock service: each member of `clients` hands out the
+/// keys for its `begin_reqs` and answers them with [`LockResponse::Began`].
+pub fn begin_transactions<'a, D: Deploy<'a>>(
+    clients: &D::Cluster,
+    begin_reqs: Stream<'a, (), stream::Windowed, D::Cluster>,
+) -> Stream<'a, LockResponse, stream::Windowed, D::Cluster> {
+    let client_id = clients.self_id();
+    let begin_batches = begin_reqs
+        .map(q!(move |()| MachineId(client_id as usize)))
+        .fold(
+            q!(Vec::new),
+            q!(|batch: &mut Vec<MachineId>, machine| batch.push(machine)),
+        )
+        .map(q!(|batch| (
+            SystemTime::now().duration_since(UNIX_EPOCH).unwrap(),
+            batch
+        )));
+    let transaction_ids = begin_batches.all_ticks().fold(
+        q!(|| Rc::new(RefCell::new(TransactionIds::default()))),
+        q!(
+            |ids: &mut Rc<RefCell<TransactionIds>>, (now, batch): (Duration, Vec<MachineId>)| {
+                ids.borrow_mut().apply_batch(now, batch)
+            }
+        ),
+    );
+    begin_batches
+        .map(q!(|_| ()))
+        .cross_product(&transaction_ids)
+        .flat_map(q!(|(_, ids): ((), Rc<RefCell<TransactionIds>>)| {
+            std::mem::take(&mut ids.borrow_mut().issued)
+        }))
+        .map(q!(|key| LockResponse::Began { key }))
+}
+
+/// Routes requests from the members of `clients` to the lock service sharded across `cluster`, and
+/// returns the responses at the client that issued each request. Clients send acquires and
+/// releases straight to the shard owning the entity, and each shard keeps only its own entities;
+/// commits, aborts and lease renewals go to every shard, and each one answers them.
+///
+/// Every `detection_interval`, each shard ships its waits-for edges to `coordinator`, which looks
+/// for cycles across all shards and has the victims chosen by `victim_policy` aborted.
+///
+/// Every `expiry_interval`, shards withdraw acquires that waited past their timeout and abort
+/// transactions whose lease ran out, as set in `config`. They also abort every transaction of a
+/// machine that shows up in `failed_machines`.
+///
+/// With a `log_dir`, each shard keeps a write-ahead log there and replays it when it starts, so
+/// a restarted shard comes back with the locks and waiters it had.
+///
+/// Transactions are started where the requests come in; see [`begin_transactions`].
+#[allow(clippy::too_many_arguments)]
+pub fn process_client_requests<'a, D: Deploy<'a>>(
+    clients: &D::Cluster,
+    coordinator: &D::Process,
+    cluster: &D::Cluster,
+    begin_reqs: Stream<'a, (), stream::Windowed, D::Cluster>,
+    requests: Stream<'a, (Key, ClientRequest), stream::Windowed, D::Cluster>,
+    failed_machines: Stream<'a, MachineId, stream::Windowed, D::Cluster>,
+    detection_interval: impl Quoted<'a, Duration> + Copy + 'a,
+    victim_policy: impl Quoted<'a, VictimPolicy> + Copy + 'a,
+    expiry_interval: impl Quoted<'a, Duration> + Copy + 'a,
+    config: impl Quoted<'a, LockConfig> + Copy + 'a,
+    log_dir: impl Quoted<'a, Option<PathBuf>> + Copy + 'a,
+) -> Stream<'a, LockResponse, stream::Windowed, D::Cluster> {
+    let began = begin_transactions(clients, begin_reqs);
+
+    let ids = cluster.ids();
+    let self_id = cluster.self_id();
+    let entity_reqs = requests
+        .filter_map(q!(move |(key, request): (Key, ClientRequest)| {
+            let shard = ids[shard_of(request.entity()?, ids.len())];
+            Some((shard, (key, request)))
+        }))
+        .demux_bincode_tagged(cluster);
+    let transaction_reqs = requests
+        .filter(q!(|(_, request): &(Key, ClientRequest)| request
+            .entity()
+            .is_none()))
+        .broadcast_bincode_tagged(cluster);
+
+    let reports = cluster
+        .source_interval(detection_interval)
+        .tick_batch()
+        .map(q!(|_| LockInput::ReportWaitsFor));
+    let expiries = cluster
+        .source_interval(expiry_interval)
+        .tick_batch()
+        .map(q!(|_| LockInput::Expire(
+            SystemTime::now().duration_since(UNIX_EPOCH).unwrap()
+        )));
+
+    // victims come back from the coordinator, which is downstream of the lock table
+    let (complete_victims, victims) = cluster.cycle();
+
+    // at the lock service: gather each tick's inputs into one batch
+    let batches = entity_reqs
+        .union(&transaction_reqs)
+        .tick_batch()
+        .map(q!(|(client, (key, request))| LockInput::Request(
+            client, key, request
+        )))
+        .union(&reports)
+        .union(&expiries)
+        .union(&failed_machines.map(q!(|machine| LockInput::MachineFailed(machine))))
+        .union(
+            &victims
+                .tick_batch()
+                .map(q!(|key| LockInput::AbortVictim(key))),
+        )
+        .fold(
+            q!(Vec::new),
+            q!(|batch: &mut Vec<LockInput>, input| batch.push(input)),
+        );
+
+    // the lock table is persistent state across ticks (all_ticks()); the fold hands out a shared
+    // handle to it rather than a copy of the whole table every tick
+    let manager = batches.all_ticks().fold(
+        q!(move || {
+            let shard = ids.iter().position(|id| *id == self_id).unwrap();
+            let manager = LockManager::new_shard(config, shard, ids.len());
+            let manager = match log_dir {
+                Some(dir) => {
+                    std::fs::create_dir_all(&dir).unwrap();
+                    manager
+                        .recover(dir.join(format!("shard-{}.wal", shard)))
+                        .unwrap()
+                }
+                None => manager,
+            };
+            Rc::new(RefCell::new(manager))
+        }),
+        q!(
+            |manager: &mut Rc<RefCell<LockManager>>, batch: Vec<LockInput>| manager
+                .borrow_mut()
+                .apply_batch(batch)
+        ),
+    );
+
+    // only ticks that received a batch have fresh responses and reports, which are taken out of
+    // the manager as they are sent
+    let fresh = batches
+        .map(q!(|_| ()))
+        .cross_product(&manager)
+        .map(q!(|(_, manager): ((), Rc<RefCell<LockManager>>)| manager));
+
+    // at the coordinator: keep the latest report of every shard and look for cycles periodically
+    let rounds = coordinator
+        .source_interval(detection_interval)
+        .tick_batch()
+        .map(q!(move |_| CoordinatorInput::Round(victim_policy)));
+    let coordinator_batches = fresh
+        .flat_map(q!(|manager: Rc<RefCell<LockManager>>| manager
+            .borrow_mut()
+            .report
+            .take()))
+        .send_bincode_tagged(coordinator)
+        .tick_batch()
+        .map(q!(|(shard, report)| CoordinatorInput::Report(
+            shard, report
+        )))
+        .union(&rounds)
+        .fold(
+            q!(Vec::new),
+            q!(|batch: &mut Vec<CoordinatorInput<Key>>, input| batch.push(input)),
+        );
+    let detector = coordinator_batches.all_ticks().fold(
+        q!(|| Rc::new(RefCell::new(DeadlockCoordinator::default()))),
+        q!(|detector: &mut Rc<RefCell<DeadlockCoordinator<Key>>>,
+            batch: Vec<CoordinatorInput<Key>>| detector.borrow_mut().apply_batch(batch)),
+    );
+    complete_victims.complete(
+        &coordinator_batches
+            .map(q!(|_| ()))
+            .cross_product(&detector)
+            .map(q!(|(_, detector)| detector))
+            .flat_map(q!(|detector: Rc<RefCell<DeadlockCoordinator<Key>>>| {
+                std::mem::take(&mut detector.borrow_mut().victims)
+            }))
+            .broadcast_bincode(cluster),
+    );
+
+    fresh
+        .flat_map(q!(|manager: Rc<RefCell<LockManager>>| std::mem::take(
+            &mut manager.borrow_mut().outbox
+        )))
+        .demux_bincode(clients)
+        .tick_batch()
+        .union(&began)
+}
+
+/// Runs the lock service for clients that use [`LockClient`]s, with `client_ports` being the
+/// dataflow's side of the client on each client member. Returns the clients.
 pub fn first_ten_distributed<'a, D: Deploy<'a>>(
     flow: &'a FlowBuilder<'a, D>,
     process_spec: &impl ProcessSpec<'a, D>,
     cluster_spec: &impl ClusterSpec<'a, D>,
-) {
-    let process = flow.process(process_spec);
-    // let second_process = flow.process(process_spec);
+    client_ports: RuntimeData<ClientPorts>,
+) -> D::Cluster {
+    serve_lock_clients(
+        flow,
+        process_spec,
+        cluster_spec,
+        client_ports,
+        q!(VictimPolicy::Youngest),
+        q!(LockConfig::default()),
+        q!(std::env::var_os("LOCK_LOG_DIR").map(PathBuf::from)),
+    )
+}
+
+/// [`first_ten_distributed`] with every shard logging into `lock_recovery` in its machine's
+/// temporary directory, which outlives the processes, so a killed service starts again where it
+/// left off.
+pub fn lock_recovery<'a, D: Deploy<'a>>(
+    flow: &'a FlowBuilder<'a, D>,
+    process_spec: &impl ProcessSpec<'a, D>,
+    cluster_spec: &impl ClusterSpec<'a, D>,
+    client_ports: RuntimeData<ClientPorts>,
+) -> D::Cluster {
+    serve_lock_clients(
+        flow,
+        process_spec,
+        cluster_spec,
+        client_ports,
+        q!(VictimPolicy::Youngest),
+        q!(LockConfig::default()),
+        q!(Some(std::env::temp_dir().join("lock_recovery"))),
+    )
+}
+
+/// [`first_ten_distributed`] with deadlock victims chosen by `victim_policy`, the lock manager
+/// set up by `config` and each shard's write-ahead log kept in `log_dir`.
+pub fn serve_lock_clients<'a, D: Deploy<'a>>(
+    flow: &'a FlowBuilder<'a, D>,
+    process_spec: &impl ProcessSpec<'a, D>,
+    cluster_spec: &impl ClusterSpec<'a, D>,
+    client_ports: RuntimeData<ClientPorts>,
+    victim_policy: impl Quoted<'a, VictimPolicy> + Copy + 'a,
+    config: impl Quoted<'a, LockConfig> + Copy + 'a,
+    log_dir: impl Quoted<'a, Option<PathBuf>> + Copy + 'a,
+) -> D::Cluster {
+    let coordinator = flow.process(process_spec);
+    let clients = flow.cluster(cluster_spec);
     let cluster = flow.cluster(cluster_spec);
 
-    let numbers = process.source_iter(q!(0..10));
-    numbers
+    let begin_reqs = clients.source_stream(q!(client_ports.begins)).tick_batch();
+    let requests = clients
+        .source_stream(q!(client_ports.requests))
+        .tick_batch();
+    // clients are identified by their member id, and report the ones they see fail to every shard
+    let failed_machines = failure_detector(&clients, q!(0.0))
+        .map(q!(|id| MachineId(id as usize)))
         .broadcast_bincode(&cluster)
-        .for_each(q!(|n| println!("{}", n)));
+        .tick_batch();
+
+    process_client_requests(
+        &clients,
+        &coordinator,
+        &cluster,
+        begin_reqs,
+        requests,
+        failed_machines,
+        q!(Duration::from_millis(1000)),
+        victim_policy,
+        q!(Duration::from_millis(100)),
+        config,
+        log_dir,
+    )
+    .for_each(q!(move |response| client_ports.responses.deliver(response)));
 
-    // second_process
+    clients
+}
+
+/// Two transactions that each lock one entity and then wait for the other's, with the entities on
+/// different shards, so only the coordinator can see the deadlock. The first client member prints
+/// how the second acquire of each ends.
+pub async fn deadlock_demo(client: LockClient) {
+    let first = client.begin().await;
+    let reporting = first.machine_id == MachineId(0);
+    let second = client.begin().await;
+    let entity = |root| EntityPath(vec![EntityId(root)]);
+
+    client.acquire(first, entity(0), LockMode::X).await.unwrap();
+    client
+        .acquire(second, entity(1), LockMode::X)
+        .await
+        .unwrap();
+    let (first_result, second_result) = tokio::join!(
+        client.acquire(first, entity(1), LockMode::S),
+        client.acquire(second, entity(0), LockMode::S),
+    );
+    if reporting {
+        println!("{:?}", first_result);
+        println!("{:?}", second_result);
+    }
+
+    // one of them was aborted to break the deadlock, and has nothing left to commit
+    let _ = tokio::join!(client.commit(first), client.commit(second));
+}
+
+/// Takes an exclusive lock and never lets go of it, with the first client member printing whether
+/// it was free. A service that was restarted from its log still has the lock of the run before, so
+/// the second run finds it held.
+pub async fn recovery_demo(client: LockClient) {
+    let key = client.begin().await;
+    let result = client
+        .try_acquire(key, EntityPath(vec![EntityId(0)]), LockMode::X)
+        .await;
+    if key.machine_id == MachineId(0) {
+        println!("{:?}", result);
+    }
 }
 
 use hydroflow_plus::util::cli::HydroCLI;
@@ -134,45 +1308,777 @@ use hydroflow_plus_cli_integration::{CLIRuntime, HydroflowPlusMeta};
 pub fn first_ten_distributed_runtime<'a>(
     flow: &'a FlowBuilder<'a, CLIRuntime>,
     cli: RuntimeData<&'a HydroCLI<HydroflowPlusMeta>>,
+    client_ports: RuntimeData<ClientPorts>,
 ) -> impl Quoted<'a, Hydroflow<'a>> {
     // let _ =
-    first_ten_distributed(flow, &cli, &cli);
+    first_ten_distributed(flow, &cli, &cli, client_ports);
+    flow.build(q!(cli.meta.subgraph_id))
+}
+
+#[stageleft::entry]
+pub fn lock_recovery_runtime<'a>(
+    flow: &'a FlowBuilder<'a, CLIRuntime>,
+    cli: RuntimeData<&'a HydroCLI<HydroflowPlusMeta>>,
+    client_ports: RuntimeData<ClientPorts>,
+) -> impl Quoted<'a, Hydroflow<'a>> {
+    lock_recovery(flow, &cli, &cli, client_ports);
     flow.build(q!(cli.meta.subgraph_id))
 }
 
 #[stageleft::runtime]
 #[cfg(test)]
 mod tests {
+    use std::cell::RefCell;
+    use std::time::Duration;
+
     use hydro_deploy::{Deployment, HydroflowCrate};
-    use hydroflow_plus::futures::StreamExt;
-    use hydroflow_plus_cli_integration::{DeployCrateWrapper, DeployProcessSpec};
-
-    // #[tokio::test]
-    // async fn first_ten_distributed() {
-    //     let mut deployment = Deployment::new();
-    //     let localhost = deployment.Localhost();
-
-    //     let flow = hydroflow_plus::FlowBuilder::new();
-    //     let second_process = super::first_ten_distributed(
-    //         &flow,
-    //         &DeployProcessSpec::new(|| {
-    //             deployment.add_service(
-    //                 HydroflowCrate::new(".", localhost.clone())
-    //                     .bin("first_ten_distributed")
-    //                     .profile("dev"),
-    //             )
-    //         }),
-    //     );
-
-    //     deployment.deploy().await.unwrap();
-
-    //     let second_process_stdout = second_process.stdout().await;
-
-    //     deployment.start().await.unwrap();
-
-    //     assert_eq!(
-    //         second_process_stdout.take(10).collect::<Vec<_>>().await,
-    //         vec!["0", "1", "2", "3", "4", "5", "6", "7", "8", "9"]
-    //     );
-    // }
+    use hydroflow_plus::futures::{self, StreamExt};
+    use hydroflow_plus_cli_integration::{
+        DeployClusterSpec, DeployCrateWrapper, DeployProcessSpec,
+    };
+    use stageleft::RuntimeData;
+
+    use super::{
+        shard_of, AbortCause, ClientRequest, DenyReason, EntityId, EntityPath, Key, LockConfig,
+        LockInput, LockManager, LockRequest, LockResponse, LogRecord, MachineId, QueueSnapshot,
+        TransactionId, TransactionIds, WaiterSnapshot,
+    };
+    use crate::deadlock::Prevention;
+    use crate::lock_mode::LockMode;
+    use crate::wal::WriteAheadLog;
+
+    fn key(transaction_id: usize) -> Key {
+        Key {
+            transaction_id: TransactionId(transaction_id),
+            machine_id: MachineId(0),
+        }
+    }
+
+    fn responses(manager: &LockManager) -> Vec<LockResponse> {
+        manager
+            .outbox
+            .iter()
+            .map(|(_, response)| response.clone())
+            .collect()
+    }
+
+    fn acquire(transaction_id: usize, entity: usize, mode: LockMode) -> LockInput {
+        LockInput::Request(
+            0,
+            key(transaction_id),
+            ClientRequest::Acquire {
+                entity: EntityPath(vec![EntityId(entity)]),
+                mode,
+            },
+        )
+    }
+
+    fn acquire_within(
+        transaction_id: usize,
+        entity: usize,
+        mode: LockMode,
+        timeout: Duration,
+    ) -> LockInput {
+        LockInput::Request(
+            0,
+            key(transaction_id),
+            ClientRequest::AcquireWithin {
+                entity: EntityPath(vec![EntityId(entity)]),
+                mode,
+                timeout,
+            },
+        )
+    }
+
+    #[test]
+    fn wait_die_aborts_younger_requester() {
+        let mut manager = LockManager::new(LockConfig {
+            prevention: Some(Prevention::WaitDie),
+            ..Default::default()
+        });
+        manager.apply_batch(vec![acquire(1, 0, LockMode::X), acquire(2, 0, LockMode::S)]);
+        assert_eq!(
+            responses(&manager).last(),
+            Some(&LockResponse::Aborted {
+                key: key(2),
+                cause: AbortCause::Prevention,
+            })
+        );
+
+        manager.apply_batch(vec![acquire(0, 0, LockMode::S)]);
+        assert_eq!(
+            responses(&manager),
+            vec![LockResponse::Queued {
+                key: key(0),
+                entity: EntityPath(vec![EntityId(0)]),
+                mode: LockMode::S,
+            }]
+        );
+    }
+
+    #[test]
+    fn wound_wait_aborts_younger_holder() {
+        let mut manager = LockManager::new(LockConfig {
+            prevention: Some(Prevention::WoundWait),
+            ..Default::default()
+        });
+        manager.apply_batch(vec![acquire(1, 0, LockMode::X), acquire(2, 0, LockMode::S)]);
+        assert_eq!(manager.outbox.len(), 2);
+
+        manager.apply_batch(vec![acquire(0, 0, LockMode::S)]);
+        assert_eq!(
+            responses(&manager),
+            vec![
+                LockResponse::Queued {
+                    key: key(0),
+                    entity: EntityPath(vec![EntityId(0)]),
+                    mode: LockMode::S,
+                },
+                LockResponse::Aborted {
+                    key: key(1),
+                    cause: AbortCause::Prevention,
+                },
+                LockResponse::Granted {
+                    key: key(2),
+                    entity: EntityPath(vec![EntityId(0)]),
+                    mode: LockMode::S,
+                },
+                LockResponse::Granted {
+                    key: key(0),
+                    entity: EntityPath(vec![EntityId(0)]),
+                    mode: LockMode::S,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn expires_waits_past_their_timeout() {
+        let secs = Duration::from_secs;
+        let mut manager = LockManager::new(LockConfig {
+            default_timeout: Some(secs(10)),
+            ..Default::default()
+        });
+        manager.apply_batch(vec![
+            acquire(0, 0, LockMode::X),
+            acquire_within(1, 0, LockMode::X, secs(5)),
+            acquire(2, 0, LockMode::S),
+        ]);
+
+        // the first tick after queueing starts the clock
+        manager.apply_batch(vec![LockInput::Expire(secs(100))]);
+        manager.apply_batch(vec![LockInput::Expire(secs(104))]);
+        assert_eq!(manager.outbox, vec![]);
+
+        manager.apply_batch(vec![LockInput::Expire(secs(105))]);
+        assert_eq!(
+            responses(&manager),
+            vec![LockResponse::TimedOut {
+                key: key(1),
+                entity: EntityPath(vec![EntityId(0)]),
+                mode: LockMode::X,
+            }]
+        );
+
+        manager.apply_batch(vec![LockInput::Expire(secs(110))]);
+        assert_eq!(manager.outbox.len(), 1);
+        assert!(!manager.table.table().involves(&key(2)));
+    }
+
+    #[test]
+    fn try_acquire_never_queues() {
+        let mut manager = LockManager::new(LockConfig::default());
+        let try_acquire = |transaction_id, mode| {
+            let entity = EntityPath(vec![EntityId(0)]);
+            LockInput::Request(
+                0,
+                key(transaction_id),
+                ClientRequest::TryAcquire { entity, mode },
+            )
+        };
+        manager.apply_batch(vec![
+            try_acquire(0, LockMode::S),
+            try_acquire(1, LockMode::X),
+        ]);
+        assert_eq!(
+            responses(&manager)[1],
+            LockResponse::Denied {
+                key: key(1),
+                request: ClientRequest::TryAcquire {
+                    entity: EntityPath(vec![EntityId(0)]),
+                    mode: LockMode::X,
+                },
+                reason: DenyReason::WouldWait,
+            }
+        );
+        assert!(!manager.table.table().involves(&key(1)));
+
+        manager.apply_batch(vec![try_acquire(2, LockMode::S)]);
+        assert!(matches!(
+            responses(&manager)[..],
+            [LockResponse::Granted { .. }]
+        ));
+    }
+
+    #[test]
+    fn try_acquire_leaves_ancestors_alone() {
+        let mut manager = LockManager::new(LockConfig::default());
+        let path =
+            |entities: &[usize]| EntityPath(entities.iter().copied().map(EntityId).collect());
+        let request = |transaction_id, request| LockInput::Request(0, key(transaction_id), request);
+        manager.apply_batch(vec![
+            request(
+                0,
+                ClientRequest::Acquire {
+                    entity: path(&[0, 1]),
+                    mode: LockMode::S,
+                },
+            ),
+            request(
+                1,
+                ClientRequest::Acquire {
+                    entity: path(&[0, 2]),
+                    mode: LockMode::S,
+                },
+            ),
+            // IS would convert to IX on the parent, but the child is held
+            request(
+                0,
+                ClientRequest::TryAcquire {
+                    entity: path(&[0, 2]),
+                    mode: LockMode::X,
+                },
+            ),
+        ]);
+        assert!(matches!(
+            responses(&manager)[2],
+            LockResponse::Denied {
+                reason: DenyReason::WouldWait,
+                ..
+            }
+        ));
+        let parent = manager.table.table().queue(&path(&[0])).unwrap();
+        assert_eq!(parent.held_mode(&key(0)), Some(LockMode::IS));
+    }
+
+    #[test]
+    fn denies_acquires_beyond_max_entities() {
+        let mut manager = LockManager::new(LockConfig {
+            max_entities: Some(2),
+            ..Default::default()
+        });
+        manager.apply_batch(vec![
+            acquire(0, 0, LockMode::X),
+            acquire(1, 1, LockMode::X),
+            acquire(2, 2, LockMode::S),
+            acquire(2, 0, LockMode::S),
+        ]);
+        assert!(matches!(
+            responses(&manager)[..],
+            [
+                LockResponse::Granted { .. },
+                LockResponse::Granted { .. },
+                LockResponse::Denied {
+                    reason: DenyReason::Backpressure,
+                    ..
+                },
+                LockResponse::Queued { .. },
+            ]
+        ));
+
+        manager.apply_batch(vec![LockInput::Request(0, key(1), ClientRequest::Commit)]);
+        manager.apply_batch(vec![acquire(3, 2, LockMode::S)]);
+        assert!(matches!(
+            responses(&manager)[..],
+            [LockResponse::Granted { .. }]
+        ));
+    }
+
+    #[test]
+    fn inspects_queues() {
+        let secs = Duration::from_secs;
+        let mut manager = LockManager::new(LockConfig::default());
+        manager.apply_batch(vec![
+            acquire(0, 0, LockMode::S),
+            acquire(1, 0, LockMode::X),
+            acquire(2, 1, LockMode::IS),
+        ]);
+        manager.apply_batch(vec![LockInput::Expire(secs(100))]);
+        manager.apply_batch(vec![LockInput::Expire(secs(103))]);
+
+        let entity = EntityPath(vec![EntityId(0)]);
+        let inspect = ClientRequest::Inspect {
+            entity: Some(entity.clone()),
+        };
+        manager.apply_batch(vec![LockInput::Request(0, key(3), inspect)]);
+        assert_eq!(manager.usage().entities, 2);
+        assert_eq!(
+            responses(&manager),
+            vec![LockResponse::Snapshot {
+                key: key(3),
+                entity: Some(entity.clone()),
+                shard: 0,
+                shards: 1,
+                queues: vec![QueueSnapshot {
+                    entity,
+                    group_mode: LockMode::S,
+                    holders: vec![(key(0), LockMode::S)],
+                    conversions: vec![],
+                    waiters: vec![WaiterSnapshot {
+                        key: key(1),
+                        mode: LockMode::X,
+                        waited: Some(secs(3)),
+                    }],
+                }],
+                usage: manager.usage(),
+            }]
+        );
+
+        assert_eq!(manager.inspect(None).len(), 2);
+    }
+
+    #[test]
+    fn forgets_idle_transactions() {
+        let secs = Duration::from_secs;
+        let mut manager = LockManager::new(LockConfig::default());
+        let entity = EntityPath(vec![EntityId(0)]);
+        manager.apply_batch(vec![
+            acquire(0, 0, LockMode::X),
+            acquire_within(1, 0, LockMode::S, secs(5)),
+            LockInput::Request(0, key(2), ClientRequest::Inspect { entity: None }),
+            LockInput::Request(0, key(3), ClientRequest::Release { entity }),
+        ]);
+        assert_eq!(
+            manager.reply_to.keys().collect::<Vec<_>>(),
+            [&key(0), &key(1)]
+        );
+
+        manager.apply_batch(vec![LockInput::Expire(secs(100))]);
+        manager.apply_batch(vec![LockInput::Expire(secs(105))]);
+        assert!(matches!(
+            responses(&manager)[..],
+            [LockResponse::TimedOut { .. }]
+        ));
+        assert_eq!(manager.reply_to.keys().collect::<Vec<_>>(), [&key(0)]);
+    }
+
+    #[test]
+    fn aborts_transactions_without_lease() {
+        let secs = Duration::from_secs;
+        let mut manager = LockManager::new(LockConfig {
+            lease: Some(secs(10)),
+            ..Default::default()
+        });
+        manager.apply_batch(vec![acquire(0, 0, LockMode::S), acquire(1, 1, LockMode::S)]);
+        manager.apply_batch(vec![LockInput::Expire(secs(100))]);
+        manager.apply_batch(vec![LockInput::Request(
+            0,
+            key(0),
+            ClientRequest::RenewLease,
+        )]);
+        manager.apply_batch(vec![LockInput::Expire(secs(105))]);
+
+        manager.apply_batch(vec![LockInput::Expire(secs(110))]);
+        assert_eq!(
+            responses(&manager),
+            vec![LockResponse::Aborted {
+                key: key(1),
+                cause: AbortCause::LeaseExpired,
+            }]
+        );
+        assert!(manager.table.table().involves(&key(0)));
+    }
+
+    #[test]
+    fn aborts_transactions_of_failed_machine() {
+        let mut manager = LockManager::new(LockConfig::default());
+        let other = Key {
+            transaction_id: TransactionId(2),
+            machine_id: MachineId(1),
+        };
+        manager.apply_batch(vec![
+            acquire(0, 0, LockMode::X),
+            acquire(1, 1, LockMode::S),
+            LockInput::Request(
+                1,
+                other,
+                ClientRequest::Acquire {
+                    entity: EntityPath(vec![EntityId(0)]),
+                    mode: LockMode::S,
+                },
+            ),
+        ]);
+
+        manager.apply_batch(vec![LockInput::MachineFailed(MachineId(0))]);
+        assert!(!manager.table.table().involves(&key(0)));
+        assert!(!manager.table.table().involves(&key(1)));
+        assert!(manager.outbox.contains(&(
+            1,
+            LockResponse::Granted {
+                key: other,
+                entity: EntityPath(vec![EntityId(0)]),
+                mode: LockMode::S,
+            }
+        )));
+    }
+
+    #[test]
+    fn shards_only_own_entities() {
+        let entity = |root| EntityPath(vec![EntityId(root)]);
+        let shards = (0..100).map(|root| shard_of(&entity(root), 4));
+        assert!((0..4).all(|shard| shards.clone().filter(|s| *s == shard).count() >= 20));
+
+        let mut manager = LockManager::new_shard(LockConfig::default(), 1, 4);
+        let owned = (0..).find(|root| shard_of(&entity(*root), 4) == 1).unwrap();
+        let other = (0..).find(|root| shard_of(&entity(*root), 4) != 1).unwrap();
+        manager.apply_batch(vec![
+            acquire(0, owned, LockMode::S),
+            acquire(0, other, LockMode::S),
+        ]);
+        assert!(matches!(
+            manager.outbox[0],
+            (_, LockResponse::Granted { .. })
+        ));
+        assert!(matches!(manager.outbox[1], (_, LockResponse::Error { .. })));
+    }
+
+    #[test]
+    fn two_phase_locking() {
+        let mut manager = LockManager::new(LockConfig::default());
+        manager.apply_batch(vec![
+            acquire(0, 0, LockMode::X),
+            acquire(0, 1, LockMode::X),
+            acquire(1, 1, LockMode::S),
+            LockInput::Request(
+                0,
+                key(0),
+                ClientRequest::Release {
+                    entity: EntityPath(vec![EntityId(0)]),
+                },
+            ),
+            acquire(0, 2, LockMode::S),
+        ]);
+        assert!(matches!(
+            responses(&manager).last(),
+            Some(LockResponse::Denied { .. })
+        ));
+
+        // commit releases the rest at once, and the next transaction starts growing again
+        manager.apply_batch(vec![
+            LockInput::Request(0, key(0), ClientRequest::Commit),
+            acquire(0, 2, LockMode::S),
+        ]);
+        assert_eq!(
+            responses(&manager),
+            vec![
+                LockResponse::Committed {
+                    key: key(0),
+                    shard: 0,
+                    shards: 1,
+                },
+                LockResponse::Granted {
+                    key: key(1),
+                    entity: EntityPath(vec![EntityId(1)]),
+                    mode: LockMode::S,
+                },
+                LockResponse::Granted {
+                    key: key(0),
+                    entity: EntityPath(vec![EntityId(2)]),
+                    mode: LockMode::S,
+                },
+            ]
+        );
+        assert_eq!(manager.table.table().locks_held(&key(1)), 1);
+    }
+
+    #[test]
+    fn grants_report_converted_mode() {
+        let mut manager = LockManager::new(LockConfig::default());
+        manager.apply_batch(vec![
+            acquire(0, 0, LockMode::S),
+            acquire(1, 0, LockMode::IS),
+            acquire(0, 0, LockMode::IX),
+        ]);
+        assert_eq!(
+            responses(&manager).last(),
+            Some(&LockResponse::Granted {
+                key: key(0),
+                entity: EntityPath(vec![EntityId(0)]),
+                mode: LockMode::SIX,
+            })
+        );
+
+        // a conversion that has to wait reports the joined mode once its plan finishes
+        let row = EntityPath(vec![EntityId(1), EntityId(0)]);
+        let acquire_row = |transaction_id, mode| {
+            LockInput::Request(
+                0,
+                key(transaction_id),
+                ClientRequest::Acquire {
+                    entity: row.clone(),
+                    mode,
+                },
+            )
+        };
+        manager.apply_batch(vec![
+            acquire_row(2, LockMode::IX),
+            acquire_row(3, LockMode::IX),
+            acquire_row(2, LockMode::S),
+        ]);
+        assert_eq!(
+            responses(&manager).last(),
+            Some(&LockResponse::Queued {
+                key: key(2),
+                entity: row.clone(),
+                mode: LockMode::S,
+            })
+        );
+
+        manager.apply_batch(vec![LockInput::Request(0, key(3), ClientRequest::Commit)]);
+        assert_eq!(
+            responses(&manager).last(),
+            Some(&LockResponse::Granted {
+                key: key(2),
+                entity: row.clone(),
+                mode: LockMode::SIX,
+            })
+        );
+    }
+
+    #[test]
+    fn replicas_respond_alike() {
+        // one transaction holds many entities with a waiter each, and its commit grants them all
+        let mut batch = (0..64)
+            .map(|entity| acquire(0, entity, LockMode::X))
+            .collect::<Vec<_>>();
+        batch.extend((0..64).map(|entity| acquire(entity + 1, entity, LockMode::S)));
+        batch.push(LockInput::Request(0, key(0), ClientRequest::Commit));
+
+        let mut first = LockManager::new(LockConfig::default());
+        let mut second = LockManager::new(LockConfig::default());
+        first.apply_batch(batch.clone());
+        second.apply_batch(batch);
+        assert_eq!(responses(&first), responses(&second));
+    }
+
+    #[test]
+    fn recovers_from_log() {
+        let path = std::env::temp_dir().join(format!("lock-manager-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let mut manager = LockManager::new(LockConfig::default())
+            .recover(&path)
+            .unwrap();
+        manager.apply_batch(vec![acquire(0, 0, LockMode::X), acquire(1, 0, LockMode::S)]);
+        manager.apply_batch(vec![acquire(1, 1, LockMode::X)]);
+        // the process dies, and the restarted one starts from the log alone
+        drop(manager);
+
+        let mut manager = LockManager::new(LockConfig::default())
+            .recover(&path)
+            .unwrap();
+        let queue = manager
+            .table
+            .table()
+            .queue(&EntityPath(vec![EntityId(0)]))
+            .unwrap();
+        assert_eq!(queue.holders(), &[(key(0), LockMode::X)]);
+        assert!(queue.waiters().eq([&(key(1), LockMode::S)]));
+        assert_eq!(manager.table.table().locks_held(&key(1)), 1);
+        assert_eq!(manager.outbox, vec![]);
+
+        manager.apply_batch(vec![LockInput::Request(0, key(0), ClientRequest::Commit)]);
+        assert_eq!(
+            responses(&manager)[1],
+            LockResponse::Granted {
+                key: key(1),
+                entity: EntityPath(vec![EntityId(0)]),
+                mode: LockMode::S,
+            }
+        );
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn compacts_log_into_snapshot() {
+        let path = std::env::temp_dir().join(format!("lock-compaction-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let mut manager = LockManager::new(LockConfig::default())
+            .recover(&path)
+            .unwrap();
+        manager.apply_batch(vec![acquire(0, 0, LockMode::X)]);
+        let size = std::fs::metadata(&path).unwrap().len();
+        // nothing waits or has a lease, so the ticks are not worth logging
+        manager.apply_batch(vec![
+            LockInput::ReportWaitsFor,
+            LockInput::Expire(Duration::from_secs(1)),
+        ]);
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), size);
+
+        manager.compact_at = 0;
+        manager.apply_batch(vec![acquire(1, 0, LockMode::S)]);
+        let (_, records) = WriteAheadLog::<LogRecord>::open(&path).unwrap();
+        assert!(matches!(records[..], [LogRecord::Snapshot(_)]));
+        drop(manager);
+
+        let mut manager = LockManager::new(LockConfig::default())
+            .recover(&path)
+            .unwrap();
+        let queue = manager
+            .table
+            .table()
+            .queue(&EntityPath(vec![EntityId(0)]))
+            .unwrap();
+        assert_eq!(queue.holders(), &[(key(0), LockMode::X)]);
+        assert!(queue.waiters().eq([&(key(1), LockMode::S)]));
+
+        // batches after the snapshot are replayed on top of it
+        manager.apply_batch(vec![LockInput::Request(0, key(0), ClientRequest::Commit)]);
+        drop(manager);
+        let manager = LockManager::new(LockConfig::default())
+            .recover(&path)
+            .unwrap();
+        assert_eq!(manager.table.table().locks_held(&key(1)), 1);
+        drop(manager);
+
+        // a member set up differently does not take over the config of the snapshot
+        let config = LockConfig {
+            default_timeout: Some(Duration::from_secs(1)),
+            ..Default::default()
+        };
+        assert!(LockManager::new(config).recover(&path).is_err());
+        assert!(LockManager::new_shard(LockConfig::default(), 0, 2)
+            .recover(&path)
+            .is_err());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn requests_round_trip() {
+        let request = LockRequest {
+            client_id: format!("client-{}", 7),
+            requested_state: LockMode::SIX,
+        };
+        let bytes = bincode::serialize(&(key(3), request.clone())).unwrap();
+        assert_eq!(
+            bincode::deserialize::<(Key, LockRequest)>(&bytes).unwrap(),
+            (key(3), request)
+        );
+
+        let acquire = acquire_within(3, 1, LockMode::S, Duration::from_millis(5));
+        let bytes = bincode::serialize(&acquire).unwrap();
+        assert_eq!(bincode::deserialize::<LockInput>(&bytes).unwrap(), acquire);
+    }
+
+    #[test]
+    fn transaction_ids_are_unique_and_ordered() {
+        let mut ids = TransactionIds::default();
+        ids.apply_batch(
+            Duration::from_millis(10),
+            vec![MachineId(0), MachineId(1), MachineId(0)],
+        );
+        let first = ids.issued.clone();
+        assert_eq!(first[0].transaction_id, first[1].transaction_id);
+        assert_ne!(first[0], first[1]);
+        assert!(first[0] < first[2]);
+
+        // a clock that went backwards still moves the ids forward
+        ids.apply_batch(Duration::from_millis(5), vec![MachineId(0)]);
+        assert!(ids.issued[0] > first[2]);
+
+        ids.apply_batch(Duration::from_millis(20), vec![MachineId(1)]);
+        assert!(ids.issued[0] > first[2]);
+    }
+
+    #[tokio::test]
+    async fn breaks_cross_shard_deadlock() {
+        let mut deployment = Deployment::new();
+        let localhost = deployment.Localhost();
+        let deployment = RefCell::new(deployment);
+        let service = || {
+            deployment.borrow_mut().add_service(
+                HydroflowCrate::new(".", localhost.clone())
+                    .bin("first_ten_distributed")
+                    .profile("dev"),
+            )
+        };
+
+        let flow = hydroflow_plus::FlowBuilder::new();
+        let clients = super::first_ten_distributed(
+            &flow,
+            &DeployProcessSpec::new(service),
+            &DeployClusterSpec::new(|| vec![service(), service()]),
+            RuntimeData::new("FAKE"),
+        );
+
+        let mut deployment = deployment.into_inner();
+        deployment.deploy().await.unwrap();
+
+        let mut stdout = futures::stream::select_all(
+            futures::future::join_all(clients.members().iter().map(|member| member.stdout())).await,
+        );
+
+        deployment.start().await.unwrap();
+
+        // every client runs the demo, and the oldest transaction of the first one's cycle outlives
+        // its victim
+        let (mut granted, mut aborted) = (false, false);
+        tokio::time::timeout(Duration::from_secs(30), async {
+            while !(granted && aborted) {
+                let line = stdout.next().await.unwrap();
+                granted |= line == "Ok(())";
+                aborted |= line.starts_with("Err(Aborted") && line.contains("cause: Deadlock");
+            }
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn keeps_locks_across_restart() {
+        let _ = std::fs::remove_dir_all(std::env::temp_dir().join("lock_recovery"));
+
+        // the first run takes the lock and is killed holding it, and the second finds it held
+        for expected in ["Ok(())", "WouldWait"] {
+            let mut deployment = Deployment::new();
+            let localhost = deployment.Localhost();
+            let deployment = RefCell::new(deployment);
+            let service = || {
+                deployment.borrow_mut().add_service(
+                    HydroflowCrate::new(".", localhost.clone())
+                        .bin("lock_recovery")
+                        .profile("dev"),
+                )
+            };
+
+            let flow = hydroflow_plus::FlowBuilder::new();
+            let clients = super::lock_recovery(
+                &flow,
+                &DeployProcessSpec::new(service),
+                &DeployClusterSpec::new(|| vec![service()]),
+                RuntimeData::new("FAKE"),
+            );
+
+            let mut deployment = deployment.into_inner();
+            deployment.deploy().await.unwrap();
+            let mut stdout = futures::stream::select_all(
+                futures::future::join_all(clients.members().iter().map(|member| member.stdout()))
+                    .await,
+            );
+            deployment.start().await.unwrap();
+
+            let line = tokio::time::timeout(Duration::from_secs(30), stdout.next())
+                .await
+                .unwrap()
+                .unwrap();
+            assert!(line.contains(expected), "{}", line);
+
+            // dropping the deployment kills every process it started
+            drop(clients);
+            drop(deployment);
+        }
+    }
 }
@@ -0,0 +1,166 @@
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
+use std::rc::Rc;
+use std::time::Duration;
+
+use hydroflow_plus::*;
+use serde::{Deserialize, Serialize};
+use stageleft::*;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Message {
+    Heartbeat,
+    HeartbeatAck,
+}
+
+/// What one member knows about whether the others are alive.
+pub enum LivenessInput {
+    /// A heartbeat went out to the member.
+    Sent(u32),
+    /// The member acked a heartbeat.
+    Acked(u32),
+    /// Time to look for members that stopped acking.
+    Check,
+}
+
+/// The heartbeats each member has left unacked, and the members already reported dead.
+#[derive(Default)]
+pub struct Liveness {
+    unacked: BTreeMap<u32, usize>,
+    /// Members reported dead, which are not reported again until they ack a heartbeat.
+    reported: BTreeSet<u32>,
+    /// Members found dead by the most recent batch.
+    pub dead: Vec<u32>,
+}
+
+impl Liveness {
+    pub fn apply_batch(&mut self, batch: Vec<LivenessInput>) {
+        self.dead.clear();
+        for input in batch {
+            match input {
+                LivenessInput::Sent(id) => *self.unacked.entry(id).or_default() += 1,
+                LivenessInput::Acked(id) => {
+                    self.unacked.insert(id, 0);
+                    self.reported.remove(&id);
+                }
+                LivenessInput::Check => {
+                    for (id, unacked) in &self.unacked {
+                        // a node has counted 3 unanswered acks in a row
+                        if *unacked > 3 && self.reported.insert(*id) {
+                            self.dead.push(*id);
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Has every member of `cluster` heartbeat every other member, and returns, once a second, the
+/// members that have missed more than three acks in a row. A member is reported once, and again
+/// only if it acks a heartbeat and then stops once more. `ack_drop_rate` artificially drops that
+/// fraction of acks, for testing.
+pub fn failure_detector<'a, D: Deploy<'a>>(
+    cluster: &D::Cluster,
+    ack_drop_rate: impl Quoted<'a, f32> + Copy + 'a,
+) -> Stream<'a, u32, stream::Windowed, D::Cluster> {
+    // members: a persistent hf+ collection of the cluster ids
+    let members = cluster.source_iter(cluster.ids()).all_ticks(); // persistent state across ticks
+
+    // generate a heartbeat every 100ms
+    let hbs = cluster
+        .source_interval(q!(Duration::from_millis(100)))
+        .map(q!(|_| Message::Heartbeat))
+        .tick_batch(); // transient state per tick
+
+    // generate a heartbeat msg for each recipient
+    let sent = hbs
+        .cross_product(&members.cloned())
+        .map(q!(|(_, id)| LivenessInput::Sent(id)));
+
+    // scatter heartbeats, gather acks
+    let acks = hbs
+        .broadcast_bincode_tagged(cluster) // broadcast to cluster, tagged with sender id
+        // at each cluster member
+        .map(q!(|(id, _m)| (id, Message::HeartbeatAck))) // generate an Ack
+        .filter(q!(move |_m| rand::random::<f32>() >= ack_drop_rate)) // artificial drop for testing
+        .demux_bincode_tagged(cluster) // return Ack to sender
+        // back at sender
+        .tick_batch()
+        .map(q!(|(id, _)| LivenessInput::Acked(id)));
+
+    // every second, check which nodes have missed their last 3 heartbeats or more
+    let checks = cluster
+        .source_interval(q!(Duration::from_millis(1000)))
+        .tick_batch() // this "pulse" is transient state
+        .map(q!(|_| LivenessInput::Check));
+
+    let batches = sent.union(&acks).union(&checks).fold(
+        q!(Vec::new),
+        q!(|batch: &mut Vec<LivenessInput>, input| batch.push(input)),
+    );
+
+    // track each nodes sent heartbeats and sets the unacked count to 0 when an ack comes in
+    // this state is persistent across ticks (all_ticks())
+    let liveness = batches.all_ticks().fold(
+        q!(|| Rc::new(RefCell::new(Liveness::default()))),
+        q!(|liveness: &mut Rc<RefCell<Liveness>>, batch| liveness.borrow_mut().apply_batch(batch)),
+    );
+
+    batches
+        .map(q!(|_| ()))
+        .cross_product(&liveness) // attach a handle to the liveness state
+        .flat_map(q!(|(_, liveness): ((), Rc<RefCell<Liveness>>)| {
+            std::mem::take(&mut liveness.borrow_mut().dead)
+        }))
+}
+
+pub fn heartbeats<'a, D: Deploy<'a>>(
+    flow: &'a FlowBuilder<'a, D>,
+    cluster_spec: &impl ClusterSpec<'a, D>,
+) -> D::Cluster {
+    let cluster = flow.cluster(cluster_spec);
+
+    failure_detector(&cluster, q!(0.7))
+        .for_each(q!(|n| println!("---------\ndead_list: {:?}\n---------", n))); // debugging
+
+    cluster
+}
+
+use hydroflow_plus::util::cli::HydroCLI;
+use hydroflow_plus_cli_integration::{CLIRuntime, HydroflowPlusMeta};
+
+#[stageleft::entry]
+pub fn heartbeats_runtime<'a>(
+    flow: &'a FlowBuilder<'a, CLIRuntime>,
+    cli: RuntimeData<&'a HydroCLI<HydroflowPlusMeta>>,
+) -> impl Quoted<'a, Hydroflow<'a>> {
+    let _ = heartbeats(flow, &cli);
+    flow.build(q!(cli.meta.subgraph_id))
+}
+
+#[stageleft::runtime]
+#[cfg(test)]
+mod tests {
+    use super::{Liveness, LivenessInput};
+
+    #[test]
+    fn reports_each_death_once() {
+        let mut liveness = Liveness::default();
+        let silent = |liveness: &mut Liveness| {
+            liveness.apply_batch((0..4).map(|_| LivenessInput::Sent(1)).collect());
+            liveness.apply_batch(vec![LivenessInput::Sent(1), LivenessInput::Check]);
+        };
+
+        silent(&mut liveness);
+        assert_eq!(liveness.dead, vec![1]);
+        silent(&mut liveness);
+        assert!(liveness.dead.is_empty());
+
+        // it comes back, and then goes quiet again
+        liveness.apply_batch(vec![LivenessInput::Acked(1), LivenessInput::Check]);
+        assert!(liveness.dead.is_empty());
+        silent(&mut liveness);
+        assert_eq!(liveness.dead, vec![1]);
+    }
+}
@@ -0,0 +1,627 @@
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+
+use lattices::Merge;
+use serde::{Deserialize, Serialize};
+
+use crate::lock_mode::LockMode;
+use crate::lock_table::{AcquireOutcome, Fairness, LockTable};
+
+/// The path from the root of the lock hierarchy down to an entity, e.g.
+/// `[database, table, page, row]`. Every prefix of a path is itself a lockable entity.
+#[derive(Clone, Debug, PartialOrd, Ord, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct EntityPath<E>(pub Vec<E>);
+
+impl<E: Clone + PartialEq> EntityPath<E> {
+    /// The proper ancestors of this entity, root first.
+    pub fn ancestors(&self) -> impl DoubleEndedIterator<Item = EntityPath<E>> + '_ {
+        (1..self.0.len()).map(|len| EntityPath(self.0[..len].to_vec()))
+    }
+
+    pub fn is_ancestor_of(&self, other: &Self) -> bool {
+        self.0.len() < other.0.len() && other.0.starts_with(&self.0)
+    }
+}
+
+/// A finished acquisition that had been waiting on some lock in its plan.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Resumed<E, H> {
+    /// The entity and the mode the holder now has on it, which a conversion may have raised past
+    /// the requested one.
+    Granted(EntityPath<E>, H, LockMode),
+    /// A later step of the plan was refused; the requested entity and mode are reported.
+    Denied(EntityPath<E>, H, LockMode, AcquireOutcome),
+}
+
+/// An acquisition that is waiting for one of its steps to be granted.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct Plan<E, H> {
+    holder: H,
+    request: (EntityPath<E>, LockMode),
+    /// The steps that are left, root first. The front one is queued in the lock table.
+    steps: VecDeque<(EntityPath<E>, LockMode)>,
+    /// The steps granted or queued so far, with what the holder had on each before, so that a plan
+    /// refused halfway can give back what it took.
+    taken: Vec<(EntityPath<E>, Option<LockMode>)>,
+}
+
+/// Multi-granularity locking over a [`LockTable`] keyed by [`EntityPath`]. Locking an entity
+/// first takes the matching intention lock on each of its ancestors, top-down, and releasing it
+/// drops those intention locks again, bottom-up, once nothing beneath them needs them.
+///
+/// A lock in S, SIX or X also covers everything beneath the entity, so acquiring or releasing
+/// something it covers does not touch the table.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(bound(
+    serialize = "E: Serialize, H: Serialize",
+    deserialize = "E: Ord + Deserialize<'de>, H: Ord + Deserialize<'de>"
+))]
+pub struct HierarchicalLockTable<E, H> {
+    table: LockTable<EntityPath<E>, H>,
+    /// The plans waiting on one of their steps, by holder.
+    parked: BTreeMap<H, Vec<Plan<E, H>>>,
+    /// How many locks a holder may have on the children of one entity before they are traded for
+    /// a single lock on the entity itself.
+    escalation_threshold: Option<usize>,
+}
+
+impl<E, H> Default for HierarchicalLockTable<E, H> {
+    fn default() -> Self {
+        Self::new(Fairness::default(), None)
+    }
+}
+
+impl<E, H> HierarchicalLockTable<E, H> {
+    /// A table whose queues grant by `fairness`, and that escalates as in
+    /// [`HierarchicalLockTable::with_escalation`] if given an `escalation_threshold`.
+    pub fn new(fairness: Fairness, escalation_threshold: Option<usize>) -> Self {
+        Self {
+            table: LockTable::new(fairness),
+            parked: BTreeMap::new(),
+            escalation_threshold,
+        }
+    }
+
+    /// A table that escalates once a holder has more than `threshold` locks on the children of
+    /// one entity, if that holder can lock the entity itself without waiting.
+    pub fn with_escalation(threshold: usize) -> Self {
+        Self::new(Fairness::default(), Some(threshold))
+    }
+}
+
+/// The mode that holding `mode` on an entity implicitly grants on everything beneath it.
+fn implied_below(mode: LockMode) -> LockMode {
+    match mode {
+        LockMode::S | LockMode::SIX => LockMode::S,
+        LockMode::X => LockMode::X,
+        LockMode::NL | LockMode::IS | LockMode::IX => LockMode::NL,
+    }
+}
+
+impl<E: Clone + Ord, H: Clone + Ord> HierarchicalLockTable<E, H> {
+    pub fn table(&self) -> &LockTable<EntityPath<E>, H> {
+        &self.table
+    }
+
+    /// See [`LockTable::take_changed`].
+    pub fn take_changed(&mut self) -> BTreeSet<EntityPath<E>> {
+        self.table.take_changed()
+    }
+
+    /// The mode `holder` has on `entity` once a request for `mode` is granted: what a conversion
+    /// turned its lock into, or `mode` itself when an ancestor's lock covers the entity.
+    pub fn granted_mode(&self, entity: &EntityPath<E>, holder: &H, mode: LockMode) -> LockMode {
+        self.table
+            .queue(entity)
+            .and_then(|queue| queue.held_mode(holder))
+            .unwrap_or(mode)
+    }
+
+    /// Locks `entity` in `mode` for `holder`, after the intention locks on its ancestors. Only
+    /// returns [`AcquireOutcome::Granted`] once every step is granted; a plan that has to wait
+    /// finishes in a later [`Resumed`].
+    pub fn acquire(&mut self, entity: EntityPath<E>, holder: H, mode: LockMode) -> AcquireOutcome {
+        if mode <= self.implied(&entity, &holder) {
+            return AcquireOutcome::Granted;
+        }
+
+        let intention = mode.intention();
+        let mut steps = VecDeque::new();
+        if intention != LockMode::NL {
+            steps.extend(entity.ancestors().map(|ancestor| (ancestor, intention)));
+        }
+        steps.push_back((entity.clone(), mode));
+
+        // refusing a later step would leave the earlier ones taken, so look for one before taking
+        // anything; the steps lock different entities, so taking one cannot change the others
+        if let Some(refused) = steps
+            .iter()
+            .find_map(|(step, mode)| self.table.refusal(step, &holder, *mode))
+        {
+            return refused;
+        }
+
+        let mut released = Vec::new();
+        let outcome = self.run(
+            Plan {
+                holder: holder.clone(),
+                request: (entity.clone(), mode),
+                steps,
+                taken: Vec::new(),
+            },
+            &mut released,
+        );
+        debug_assert!(released.is_empty());
+        if outcome == AcquireOutcome::Granted {
+            self.escalate(&entity, &holder);
+        }
+        outcome
+    }
+
+    /// Whether [`HierarchicalLockTable::acquire`] would be granted right away, found without taking
+    /// any of the intention locks on the ancestors: every step has to be granted on its own.
+    pub fn would_grant(&self, entity: &EntityPath<E>, holder: &H, mode: LockMode) -> bool {
+        if mode <= self.implied(entity, holder) {
+            return true;
+        }
+
+        let intention = mode.intention();
+        let ancestors_granted = intention == LockMode::NL
+            || entity
+                .ancestors()
+                .all(|ancestor| self.table.would_grant(&ancestor, holder, intention));
+        ancestors_granted && self.table.would_grant(entity, holder, mode)
+    }
+
+    /// Releases `holder`'s lock on `entity`, or withdraws its pending acquisition, and then its
+    /// intention locks on the ancestors that no longer cover anything. Returns `None` if the holder
+    /// had nothing on `entity`, not even through a lock on an ancestor.
+    pub fn release(&mut self, entity: &EntityPath<E>, holder: &H) -> Option<Vec<Resumed<E, H>>> {
+        let mut granted = Vec::new();
+        let mut withdrawn = false;
+        if let Some(plan) = self.unpark(holder, |plan| plan.request.0 == *entity) {
+            let (waiting_on, _) = plan.steps.front().unwrap();
+            if waiting_on != entity {
+                // whatever the holder had on the ancestor before it asked stays
+                granted.extend(self.table.cancel(waiting_on, holder).unwrap_or_default());
+                withdrawn = true;
+            }
+        }
+
+        match self.table.release(entity, holder) {
+            Some(released) => granted.extend(released),
+            None if withdrawn => {}
+            // the ancestor's lock stays, and with it this one
+            None if self.implied(entity, holder) != LockMode::NL => return Some(Vec::new()),
+            None => return None,
+        }
+
+        self.release_ancestors(entity, holder, &mut granted);
+        Some(self.resume(granted))
+    }
+
+    /// Withdraws `holder`'s pending acquisition of `entity`, keeping whatever it held before it
+    /// asked, and drops the intention locks the acquisition took that no longer cover anything.
+    /// Returns `None` if no such acquisition was waiting.
+    pub fn cancel(&mut self, entity: &EntityPath<E>, holder: &H) -> Option<Vec<Resumed<E, H>>> {
+        let plan = self.unpark(holder, |plan| plan.request.0 == *entity)?;
+        let (waiting_on, _) = plan.steps.front().unwrap();
+        let mut granted = self.table.cancel(waiting_on, holder).unwrap_or_default();
+
+        self.release_ancestors(entity, holder, &mut granted);
+        Some(self.resume(granted))
+    }
+
+    /// Releases every lock and pending acquisition of `holder`.
+    pub fn release_all(&mut self, holder: &H) -> Vec<Resumed<E, H>> {
+        self.parked.remove(holder);
+        let granted = self.table.release_all(holder);
+        self.resume(granted)
+    }
+
+    /// Releases `holder`'s intention locks above `entity`, bottom-up, until one is still needed.
+    fn release_ancestors(
+        &mut self,
+        entity: &EntityPath<E>,
+        holder: &H,
+        granted: &mut Vec<(EntityPath<E>, H, LockMode)>,
+    ) {
+        for ancestor in entity.ancestors().rev() {
+            let held = self
+                .table
+                .queue(&ancestor)
+                .and_then(|queue| queue.held_mode(holder));
+            if held.is_none() {
+                continue;
+            }
+            if !matches!(held, Some(LockMode::IS | LockMode::IX))
+                || self.holds_below(&ancestor, holder)
+            {
+                break;
+            }
+            granted.extend(self.table.release(&ancestor, holder).unwrap());
+        }
+    }
+
+    /// The strongest mode `holder` has on `entity` through its locks on the ancestors.
+    fn implied(&self, entity: &EntityPath<E>, holder: &H) -> LockMode {
+        entity
+            .ancestors()
+            .filter_map(|ancestor| self.table.queue(&ancestor)?.held_mode(holder))
+            .fold(LockMode::NL, |implied, held| {
+                Merge::merge_owned(implied, implied_below(held))
+            })
+    }
+
+    /// Trades `holder`'s locks beneath the parent of the just granted `entity` for one lock on the
+    /// parent, once it holds more children of the parent than the threshold allows. The parent is
+    /// locked in X if anything beneath it was locked for writing, otherwise in S.
+    fn escalate(&mut self, entity: &EntityPath<E>, holder: &H) {
+        let Some(threshold) = self.escalation_threshold else {
+            return;
+        };
+        let Some(parent) = entity.ancestors().last() else {
+            return;
+        };
+        let below = self
+            .table
+            .entities_of(holder)
+            .filter(|below| parent.is_ancestor_of(below))
+            .filter_map(|below| Some((below.clone(), self.table.queue(below)?.held_mode(holder)?)))
+            .collect::<Vec<_>>();
+        let children = below
+            .iter()
+            .filter(|(below, _)| below.0.len() == parent.0.len() + 1)
+            .count();
+        if children <= threshold
+            || self
+                .parked
+                .get(holder)
+                .into_iter()
+                .flatten()
+                .any(|plan| parent.is_ancestor_of(&plan.request.0))
+        {
+            return;
+        }
+
+        let target = if below
+            .iter()
+            .any(|(_, mode)| mode.intention() == LockMode::IX)
+        {
+            LockMode::X
+        } else {
+            LockMode::S
+        };
+        if !self
+            .table
+            .queue(&parent)
+            .is_some_and(|queue| queue.can_convert(holder, target))
+        {
+            return;
+        }
+        let outcome = self.table.acquire(parent, holder.clone(), target);
+        debug_assert_eq!(outcome, AcquireOutcome::Granted);
+        for (below, _) in below {
+            // anyone waiting beneath the parent would need an intention lock on it that conflicts
+            // with the escalated one, so releasing these cannot let anybody through
+            let granted = self.table.release(&below, holder).unwrap();
+            debug_assert!(granted.is_empty());
+        }
+    }
+
+    fn holds_below(&self, ancestor: &EntityPath<E>, holder: &H) -> bool {
+        self.table
+            .entities_of(holder)
+            .any(|entity| ancestor.is_ancestor_of(entity))
+    }
+
+    /// Takes the steps of `plan` until one has to wait, which parks the plan, or is refused, which
+    /// gives back the earlier ones and adds the grants that causes to `released`.
+    fn run(
+        &mut self,
+        mut plan: Plan<E, H>,
+        released: &mut Vec<(EntityPath<E>, H, LockMode)>,
+    ) -> AcquireOutcome {
+        while let Some((entity, mode)) = plan.steps.front().cloned() {
+            let before = self
+                .table
+                .queue(&entity)
+                .and_then(|queue| queue.held_mode(&plan.holder));
+            match self
+                .table
+                .acquire(entity.clone(), plan.holder.clone(), mode)
+            {
+                AcquireOutcome::Granted => {
+                    plan.taken.push((entity, before));
+                    plan.steps.pop_front();
+                }
+                AcquireOutcome::Waiting => {
+                    plan.taken.push((entity, before));
+                    self.parked
+                        .entry(plan.holder.clone())
+                        .or_default()
+                        .push(plan);
+                    return AcquireOutcome::Waiting;
+                }
+                refused => {
+                    self.give_back(&plan, released);
+                    return refused;
+                }
+            }
+        }
+        AcquireOutcome::Granted
+    }
+
+    /// Returns what the refused `plan` took on the ancestors, bottom-up: each goes back to what the
+    /// holder had on it before, or to the intention lock its other locks and plans beneath still
+    /// need, whichever is stronger.
+    fn give_back(&mut self, plan: &Plan<E, H>, released: &mut Vec<(EntityPath<E>, H, LockMode)>) {
+        let holder = &plan.holder;
+        for (ancestor, before) in plan.taken.iter().rev() {
+            let Some(held) = self
+                .table
+                .queue(ancestor)
+                .and_then(|queue| queue.held_mode(holder))
+            else {
+                continue;
+            };
+            let held_below = self
+                .table
+                .entities_of(holder)
+                .filter(|below| ancestor.is_ancestor_of(below))
+                .filter_map(|below| self.table.queue(below)?.held_mode(holder));
+            let parked_below = self
+                .parked
+                .get(holder)
+                .into_iter()
+                .flatten()
+                .filter(|other| ancestor.is_ancestor_of(&other.request.0))
+                .map(|other| other.request.1);
+            let needed = held_below
+                .chain(parked_below)
+                .fold(before.unwrap_or(LockMode::NL), |needed, mode| {
+                    Merge::merge_owned(needed, mode.intention())
+                });
+            if needed == LockMode::NL {
+                released.extend(self.table.release(ancestor, holder).unwrap());
+            } else if needed != held {
+                released.extend(self.table.downgrade(ancestor, holder, needed));
+            }
+        }
+    }
+
+    /// Takes the parked plan of `holder` that `matches`, if there is one.
+    fn unpark(&mut self, holder: &H, matches: impl Fn(&Plan<E, H>) -> bool) -> Option<Plan<E, H>> {
+        let plans = self.parked.get_mut(holder)?;
+        let plan = plans.swap_remove(plans.iter().position(matches)?);
+        if plans.is_empty() {
+            self.parked.remove(holder);
+        }
+        Some(plan)
+    }
+
+    /// Advances the plans whose current step was just granted.
+    fn resume(&mut self, granted: Vec<(EntityPath<E>, H, LockMode)>) -> Vec<Resumed<E, H>> {
+        let mut granted = VecDeque::from(granted);
+        let mut released = Vec::new();
+        let mut resumed = Vec::new();
+        while let Some((entity, holder, mode)) = granted.pop_front() {
+            let Some(mut plan) = self.unpark(&holder, |plan| {
+                plan.steps.front().map(|(e, _)| e) == Some(&entity)
+            }) else {
+                resumed.push(Resumed::Granted(entity, holder, mode));
+                continue;
+            };
+
+            plan.steps.pop_front();
+            let (requested, requested_mode) = plan.request.clone();
+            let outcome = self.run(plan, &mut released);
+            granted.extend(released.drain(..));
+            match outcome {
+                AcquireOutcome::Granted => {
+                    self.escalate(&requested, &holder);
+                    let mode = self.granted_mode(&requested, &holder, requested_mode);
+                    resumed.push(Resumed::Granted(requested, holder, mode))
+                }
+                AcquireOutcome::Waiting => {}
+                refused => {
+                    resumed.push(Resumed::Denied(requested, holder, requested_mode, refused))
+                }
+            }
+        }
+        resumed
+    }
+}
+
+#[stageleft::runtime]
+#[cfg(test)]
+mod tests {
+    use super::{EntityPath, HierarchicalLockTable, Resumed};
+    use crate::lock_mode::LockMode;
+    use crate::lock_table::AcquireOutcome;
+
+    fn held(
+        table: &HierarchicalLockTable<usize, &str>,
+        path: &[usize],
+        holder: &str,
+    ) -> Option<LockMode> {
+        table
+            .table()
+            .queue(&EntityPath(path.to_vec()))
+            .and_then(|queue| queue.held_mode(&holder))
+    }
+
+    #[test]
+    fn takes_intention_locks_top_down() {
+        let mut table = HierarchicalLockTable::default();
+        let row = EntityPath(vec![0, 1, 2]);
+        assert_eq!(
+            table.acquire(row.clone(), "joe", LockMode::X),
+            AcquireOutcome::Granted
+        );
+        assert_eq!(held(&table, &[0], "joe"), Some(LockMode::IX));
+        assert_eq!(held(&table, &[0, 1], "joe"), Some(LockMode::IX));
+        assert_eq!(held(&table, &[0, 1, 2], "joe"), Some(LockMode::X));
+
+        table.release(&row, &"joe").unwrap();
+        assert_eq!(held(&table, &[0], "joe"), None);
+        assert_eq!(held(&table, &[0, 1], "joe"), None);
+    }
+
+    #[test]
+    fn keeps_intention_locks_still_in_use() {
+        let mut table = HierarchicalLockTable::default();
+        table.acquire(EntityPath(vec![0, 1]), "joe", LockMode::S);
+        table.acquire(EntityPath(vec![0, 2]), "joe", LockMode::S);
+
+        table.release(&EntityPath(vec![0, 1]), &"joe").unwrap();
+        assert_eq!(held(&table, &[0], "joe"), Some(LockMode::IS));
+    }
+
+    #[test]
+    fn waits_on_ancestor_before_locking_leaf() {
+        let mut table = HierarchicalLockTable::default();
+        table.acquire(EntityPath(vec![0]), "joe", LockMode::S);
+        assert_eq!(
+            table.acquire(EntityPath(vec![0, 1]), "shadaj", LockMode::X),
+            AcquireOutcome::Waiting
+        );
+        assert_eq!(held(&table, &[0, 1], "shadaj"), None);
+
+        assert_eq!(
+            table.release(&EntityPath(vec![0]), &"joe").unwrap(),
+            vec![Resumed::Granted(
+                EntityPath(vec![0, 1]),
+                "shadaj",
+                LockMode::X
+            )]
+        );
+        assert_eq!(held(&table, &[0], "shadaj"), Some(LockMode::IX));
+    }
+
+    #[test]
+    fn refused_plan_gives_back_ancestors() {
+        let mut table = HierarchicalLockTable::default();
+        let row = |row| EntityPath(vec![0, row]);
+        table.acquire(row(5), "chris", LockMode::X);
+        table.acquire(row(1), "shadaj", LockMode::S);
+        table.acquire(row(1), "joe", LockMode::S);
+        table.acquire(row(7), "joe", LockMode::X);
+        table.acquire(row(9), "mingwei", LockMode::S);
+        // the root's IX holders hold up the conversion, and every conversion after it
+        assert_eq!(
+            table.acquire(EntityPath(vec![0]), "mingwei", LockMode::S),
+            AcquireOutcome::Waiting
+        );
+        assert_eq!(
+            table.acquire(row(1), "shadaj", LockMode::X),
+            AcquireOutcome::Waiting
+        );
+        assert_eq!(
+            table.acquire(row(1), "joe", LockMode::X),
+            AcquireOutcome::Waiting
+        );
+
+        // the root converts to IX, and then the row closes a cycle with the conversion queued there
+        assert_eq!(
+            table.release_all(&"mingwei"),
+            vec![Resumed::Denied(
+                row(1),
+                "shadaj",
+                LockMode::X,
+                AcquireOutcome::ConversionDeadlock
+            )]
+        );
+        assert_eq!(held(&table, &[0], "shadaj"), Some(LockMode::IS));
+        assert_eq!(held(&table, &[0, 1], "shadaj"), Some(LockMode::S));
+
+        // refused right away, before the root is converted
+        table.acquire(row(3), "mingwei", LockMode::S);
+        assert_eq!(
+            table.acquire(row(5), "mingwei", LockMode::S),
+            AcquireOutcome::Waiting
+        );
+        assert_eq!(
+            table.acquire(row(5), "mingwei", LockMode::X),
+            AcquireOutcome::AlreadyWaiting
+        );
+        assert_eq!(held(&table, &[0], "mingwei"), Some(LockMode::IS));
+    }
+
+    #[test]
+    fn cancel_keeps_earlier_grants() {
+        let mut table = HierarchicalLockTable::default();
+        table.acquire(EntityPath(vec![0, 1]), "joe", LockMode::S);
+        table.acquire(EntityPath(vec![0, 1]), "shadaj", LockMode::S);
+        assert_eq!(
+            table.acquire(EntityPath(vec![0, 1]), "joe", LockMode::X),
+            AcquireOutcome::Waiting
+        );
+        assert_eq!(
+            table.acquire(EntityPath(vec![0, 2]), "mingwei", LockMode::X),
+            AcquireOutcome::Granted
+        );
+        assert_eq!(
+            table.acquire(EntityPath(vec![0, 2]), "chris", LockMode::S),
+            AcquireOutcome::Waiting
+        );
+
+        table.cancel(&EntityPath(vec![0, 1]), &"joe").unwrap();
+        assert_eq!(held(&table, &[0, 1], "joe"), Some(LockMode::S));
+
+        table.cancel(&EntityPath(vec![0, 2]), &"chris").unwrap();
+        assert_eq!(held(&table, &[0], "chris"), None);
+        assert!(table.cancel(&EntityPath(vec![0, 2]), &"chris").is_none());
+    }
+
+    #[test]
+    fn release_of_a_waiting_leaf_keeps_intention_locks() {
+        let mut table = HierarchicalLockTable::default();
+        table.acquire(EntityPath(vec![0, 2]), "joe", LockMode::S);
+        table.acquire(EntityPath(vec![0]), "shadaj", LockMode::S);
+        // the IS on the parent has to become IX, which waits behind shadaj's S
+        assert_eq!(
+            table.acquire(EntityPath(vec![0, 1]), "joe", LockMode::X),
+            AcquireOutcome::Waiting
+        );
+
+        assert_eq!(table.release(&EntityPath(vec![0, 1]), &"joe"), Some(vec![]));
+        assert_eq!(held(&table, &[0], "joe"), Some(LockMode::IS));
+        assert_eq!(held(&table, &[0, 2], "joe"), Some(LockMode::S));
+        assert!(table
+            .table()
+            .queue(&EntityPath(vec![0]))
+            .unwrap()
+            .conversions()
+            .is_empty());
+    }
+
+    #[test]
+    fn escalates_to_parent_when_compatible() {
+        let mut table = HierarchicalLockTable::with_escalation(2);
+        table.acquire(EntityPath(vec![0, 9]), "shadaj", LockMode::S);
+        for row in 0..3 {
+            table.acquire(EntityPath(vec![0, 1, row]), "joe", LockMode::S);
+        }
+        assert_eq!(held(&table, &[0, 1], "joe"), Some(LockMode::S));
+        assert_eq!(held(&table, &[0, 1, 0], "joe"), None);
+        assert_eq!(table.table().locks_held(&"joe"), 2);
+
+        // covered by the parent, so neither touches the table
+        assert_eq!(
+            table.acquire(EntityPath(vec![0, 1, 5]), "joe", LockMode::S),
+            AcquireOutcome::Granted
+        );
+        assert_eq!(
+            table.release(&EntityPath(vec![0, 1, 0]), &"joe"),
+            Some(vec![])
+        );
+        assert_eq!(table.table().locks_held(&"joe"), 2);
+
+        // X on the parent would conflict with shadaj's IS on the root
+        for row in 2..5 {
+            table.acquire(EntityPath(vec![0, row]), "mingwei", LockMode::X);
+        }
+        assert_eq!(held(&table, &[0], "mingwei"), Some(LockMode::IX));
+        assert_eq!(table.table().locks_held(&"mingwei"), 4);
+    }
+}
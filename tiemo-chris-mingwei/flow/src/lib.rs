@@ -1,5 +1,25 @@
 stageleft::stageleft_crate!(flow_macro);
 
+pub mod deadlock;
+
 pub mod first_ten;
 
 pub mod first_ten_distributed;
+
+pub mod heartbeats;
+
+pub mod hierarchy;
+
+pub mod lock_client;
+
+pub mod lock_mode;
+
+pub mod lock_table;
+
+pub mod paxos;
+
+pub mod replicated;
+
+pub mod wal;
+
+pub mod workload;
@@ -0,0 +1,624 @@
+use std::collections::hash_map::Entry;
+use std::collections::{BTreeSet, HashMap, HashSet, VecDeque};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use hydroflow::tokio_stream::wrappers::UnboundedReceiverStream;
+use tokio::sync::mpsc::{UnboundedSender, WeakUnboundedSender};
+use tokio::sync::oneshot;
+
+use crate::first_ten_distributed::{
+    AbortCause, ClientRequest, DenyReason, EntityId, Key, LockResponse, QueueSnapshot,
+};
+use crate::hierarchy::EntityPath;
+use crate::lock_mode::LockMode;
+use crate::lock_table::TableUsage;
+
+/// How an acquire ended: granted, or refused by the response given.
+pub type AcquireResult = Result<(), LockResponse>;
+
+/// How a commit ended: acknowledged by every shard, or cut short by the response given.
+pub type CommitResult = Result<(), LockResponse>;
+
+/// Callers waiting for the lock service, by what they wait for.
+#[derive(Default)]
+struct Pending {
+    /// `begin` calls, answered in the order they were sent.
+    begins: VecDeque<oneshot::Sender<Key>>,
+    /// Acquires that have not been answered yet, of which a transaction has at most one per entity.
+    acquires: HashMap<(Key, EntityPath<EntityId>), oneshot::Sender<AcquireResult>>,
+    /// `inspect` calls that not every shard has answered yet, in the order they were sent.
+    inspections: VecDeque<PendingInspection>,
+    /// Commits that not every shard has acknowledged yet.
+    commits: HashMap<Key, PendingCommit>,
+    /// Transactions that sent a release, and so may not acquire anything on any shard until they
+    /// end. Each shard only sees its own releases, so two-phase locking is enforced here.
+    shrinking: HashSet<Key>,
+}
+
+/// What [`LockClient::inspect`] found.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Inspection {
+    /// The queues asked for, ordered by entity.
+    pub queues: Vec<QueueSnapshot>,
+    /// How much the lock tables of the shards that answered take up, added together.
+    pub usage: TableUsage,
+}
+
+/// An `inspect` call, with what the shards that answered it so far found.
+struct PendingInspection {
+    key: Key,
+    entity: Option<EntityPath<EntityId>>,
+    answered: BTreeSet<usize>,
+    found: Inspection,
+    sender: oneshot::Sender<Inspection>,
+}
+
+/// A `commit` call, with the shards that acknowledged it so far.
+struct PendingCommit {
+    answered: BTreeSet<usize>,
+    sender: oneshot::Sender<CommitResult>,
+}
+
+/// A handle application code uses to run transactions against the lock service, from the client
+/// member whose dataflow was given the matching [`ClientPorts`]. Clones share the same ports, so
+/// several tasks can run transactions at once.
+#[derive(Clone)]
+pub struct LockClient {
+    begins: UnboundedSender<()>,
+    requests: UnboundedSender<(Key, ClientRequest)>,
+    pending: Arc<Mutex<Pending>>,
+}
+
+/// The dataflow's side of a [`LockClient`]: the requests it sends, and the sink for the responses
+/// that come back.
+pub struct ClientPorts {
+    pub begins: UnboundedReceiverStream<()>,
+    pub requests: UnboundedReceiverStream<(Key, ClientRequest)>,
+    pub responses: ResponseSink,
+}
+
+pub struct ResponseSink {
+    pending: Arc<Mutex<Pending>>,
+    /// Where aborts the service started on its own are sent on to every shard. Weak, so that the
+    /// requests end once every client is dropped.
+    requests: WeakUnboundedSender<(Key, ClientRequest)>,
+}
+
+impl LockClient {
+    pub fn new() -> (Self, ClientPorts) {
+        let (begins, begins_recv) = hydroflow::util::unbounded_channel();
+        let (requests, requests_recv) = hydroflow::util::unbounded_channel();
+        let pending = Arc::new(Mutex::new(Pending::default()));
+        let client = Self {
+            begins,
+            requests,
+            pending: pending.clone(),
+        };
+        let ports = ClientPorts {
+            begins: begins_recv,
+            requests: requests_recv,
+            responses: ResponseSink {
+                pending,
+                requests: client.requests.downgrade(),
+            },
+        };
+        (client, ports)
+    }
+
+    /// Starts a transaction and returns its key, which orders it by age against every other.
+    pub async fn begin(&self) -> Key {
+        let (sender, receiver) = oneshot::channel();
+        self.pending.lock().unwrap().begins.push_back(sender);
+        self.begins.send(()).unwrap();
+        receiver.await.unwrap()
+    }
+
+    /// Acquires `entity` in `mode`, waiting as long as the service lets it queue. On failure,
+    /// returns the response that ended the wait; if that is [`LockResponse::Aborted`], the whole
+    /// transaction is gone.
+    pub async fn acquire(
+        &self,
+        key: Key,
+        entity: EntityPath<EntityId>,
+        mode: LockMode,
+    ) -> AcquireResult {
+        self.request_lock(key, ClientRequest::Acquire { entity, mode })
+            .await
+    }
+
+    /// Acquires `entity` in `mode` only if nothing stands in the way, and is denied with
+    /// [`DenyReason::WouldWait`] otherwise.
+    ///
+    /// [`DenyReason::WouldWait`]: crate::first_ten_distributed::DenyReason::WouldWait
+    pub async fn try_acquire(
+        &self,
+        key: Key,
+        entity: EntityPath<EntityId>,
+        mode: LockMode,
+    ) -> AcquireResult {
+        self.request_lock(key, ClientRequest::TryAcquire { entity, mode })
+            .await
+    }
+
+    /// Acquires `entity` in `mode`, giving up with [`LockResponse::TimedOut`] after waiting for
+    /// `timeout`.
+    pub async fn acquire_within(
+        &self,
+        key: Key,
+        entity: EntityPath<EntityId>,
+        mode: LockMode,
+        timeout: Duration,
+    ) -> AcquireResult {
+        let request = ClientRequest::AcquireWithin {
+            entity,
+            mode,
+            timeout,
+        };
+        self.request_lock(key, request).await
+    }
+
+    async fn request_lock(&self, key: Key, request: ClientRequest) -> AcquireResult {
+        let (sender, receiver) = oneshot::channel();
+        let entity = request.entity().unwrap().clone();
+        let refused = {
+            let mut pending = self.pending.lock().unwrap();
+            if pending.shrinking.contains(&key) {
+                Some(DenyReason::Shrinking)
+            } else if let Entry::Vacant(vacant) = pending.acquires.entry((key, entity)) {
+                vacant.insert(sender);
+                None
+            } else {
+                // the service would deny it too, but its answer could not be told apart from the
+                // one to the acquire already waiting
+                Some(DenyReason::AlreadyWaiting)
+            }
+        };
+        if let Some(reason) = refused {
+            return Err(LockResponse::Denied {
+                key,
+                request,
+                reason,
+            });
+        }
+        self.send(key, request);
+        receiver.await.unwrap()
+    }
+
+    /// Returns the state of the queue of `entity`, or of every queue with `None`, asking under
+    /// `key`, along with the memory the lock table takes up. A sharded service answers for the
+    /// whole table from every shard, and the answers are merged once all of them are in; one
+    /// entity is answered for by its own shard only, with that shard's usage.
+    pub async fn inspect(&self, key: Key, entity: Option<EntityPath<EntityId>>) -> Inspection {
+        let (sender, receiver) = oneshot::channel();
+        self.pending
+            .lock()
+            .unwrap()
+            .inspections
+            .push_back(PendingInspection {
+                key,
+                entity: entity.clone(),
+                answered: BTreeSet::new(),
+                found: Inspection::default(),
+                sender,
+            });
+        self.send(key, ClientRequest::Inspect { entity });
+        receiver.await.unwrap()
+    }
+
+    /// Releases `entity`, after which the transaction is denied every acquire with
+    /// [`DenyReason::Shrinking`].
+    pub fn release(&self, key: Key, entity: EntityPath<EntityId>) {
+        self.pending.lock().unwrap().shrinking.insert(key);
+        self.send(key, ClientRequest::Release { entity });
+    }
+
+    /// Commits the transaction, and returns once every shard has acknowledged it. Fails with the
+    /// response of a shard that aborted the transaction before that, or could not commit it.
+    pub async fn commit(&self, key: Key) -> CommitResult {
+        let (sender, receiver) = oneshot::channel();
+        {
+            let mut pending = self.pending.lock().unwrap();
+            pending.shrinking.remove(&key);
+            pending.commits.insert(
+                key,
+                PendingCommit {
+                    answered: BTreeSet::new(),
+                    sender,
+                },
+            );
+        }
+        self.send(key, ClientRequest::Commit);
+        receiver.await.unwrap()
+    }
+
+    pub fn abort(&self, key: Key) {
+        self.pending.lock().unwrap().shrinking.remove(&key);
+        self.send(key, ClientRequest::Abort);
+    }
+
+    pub fn renew_lease(&self, key: Key) {
+        self.send(key, ClientRequest::RenewLease);
+    }
+
+    fn send(&self, key: Key, request: ClientRequest) {
+        self.requests.send((key, request)).unwrap();
+    }
+}
+
+impl ResponseSink {
+    /// Wakes up whoever waits for `response`. Responses nobody waits for, like the ones to
+    /// releases and lease renewals, are dropped.
+    ///
+    /// A shard that aborts a transaction by itself, e.g. to prevent a deadlock or because its lease
+    /// ran out, only releases the locks it has, so the abort is sent on to every shard as if the
+    /// client had asked for it. It goes out after every request the client sent before, so none of
+    /// those can take a lock after the abort.
+    pub fn deliver(&self, response: LockResponse) {
+        let mut pending = self.pending.lock().unwrap();
+        let (key, entity) = match &response {
+            LockResponse::Began { key } => {
+                if let Some(sender) = pending.begins.pop_front() {
+                    let _ = sender.send(*key);
+                }
+                return;
+            }
+            LockResponse::Snapshot {
+                key,
+                entity,
+                shard,
+                shards,
+                queues,
+                usage,
+            } => {
+                // every shard answers in the order it was asked, so this answers the oldest
+                // inspection it has not answered yet
+                let waiting = pending.inspections.iter().position(|inspection| {
+                    inspection.key == *key
+                        && inspection.entity == *entity
+                        && !inspection.answered.contains(shard)
+                });
+                let Some(index) = waiting else {
+                    return;
+                };
+                let inspection = &mut pending.inspections[index];
+                inspection.answered.insert(*shard);
+                inspection.found.queues.extend(queues.iter().cloned());
+                inspection.found.usage += *usage;
+                if inspection.answered.len() == *shards {
+                    let mut inspection = pending.inspections.remove(index).unwrap();
+                    inspection
+                        .found
+                        .queues
+                        .sort_by(|a, b| a.entity.cmp(&b.entity));
+                    let _ = inspection.sender.send(inspection.found);
+                }
+                return;
+            }
+            LockResponse::Committed { key, shard, shards } => {
+                if let Entry::Occupied(mut commit) = pending.commits.entry(*key) {
+                    commit.get_mut().answered.insert(*shard);
+                    if commit.get().answered.len() == *shards {
+                        let _ = commit.remove().sender.send(Ok(()));
+                    }
+                }
+                return;
+            }
+            LockResponse::Error {
+                key,
+                request: ClientRequest::Commit,
+                ..
+            } => {
+                if let Some(commit) = pending.commits.remove(key) {
+                    let _ = commit.sender.send(Err(response.clone()));
+                }
+                return;
+            }
+            LockResponse::Aborted { key, cause } => {
+                if *cause != AbortCause::Requested {
+                    if let Some(requests) = self.requests.upgrade() {
+                        let _ = requests.send((*key, ClientRequest::Abort));
+                    }
+                }
+                pending.shrinking.remove(key);
+                if let Some(commit) = pending.commits.remove(key) {
+                    let _ = commit.sender.send(Err(response.clone()));
+                }
+                let aborted = pending
+                    .acquires
+                    .keys()
+                    .filter(|(waiting, _)| waiting == key)
+                    .cloned()
+                    .collect::<Vec<_>>();
+                for waiting in aborted {
+                    let sender = pending.acquires.remove(&waiting).unwrap();
+                    let _ = sender.send(Err(response.clone()));
+                }
+                return;
+            }
+            LockResponse::Granted { key, entity, .. }
+            | LockResponse::TimedOut { key, entity, .. } => (*key, entity.clone()),
+            LockResponse::Denied { key, request, .. }
+            | LockResponse::Error { key, request, .. }
+                if request.is_acquire() =>
+            {
+                (*key, request.entity().unwrap().clone())
+            }
+            _ => return,
+        };
+        if let Some(sender) = pending.acquires.remove(&(key, entity)) {
+            let result = match response {
+                LockResponse::Granted { .. } => Ok(()),
+                response => Err(response),
+            };
+            let _ = sender.send(result);
+        }
+    }
+}
+
+#[stageleft::runtime]
+#[cfg(test)]
+mod tests {
+    use hydroflow::futures::{FutureExt, StreamExt};
+    use tokio::task::JoinHandle;
+
+    use super::{ClientPorts, Inspection, LockClient};
+    use crate::deadlock::Prevention;
+    use crate::first_ten_distributed::{
+        shard_of, AbortCause, ClientRequest, DenyReason, EntityId, Key, LockConfig, LockInput,
+        LockManager, LockResponse, MachineId, TransactionId, TransactionIds,
+    };
+    use crate::hierarchy::EntityPath;
+    use crate::lock_mode::LockMode;
+
+    /// Stands in for the dataflow, running each request through the lock manager of every shard it
+    /// is routed to, on its own.
+    fn serve(mut ports: ClientPorts, num_shards: usize, config: LockConfig) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut ids = TransactionIds::default();
+            let mut shards = (0..num_shards)
+                .map(|shard| LockManager::new_shard(config, shard, num_shards))
+                .collect::<Vec<_>>();
+            loop {
+                tokio::select! {
+                    Some(()) = ports.begins.next() => {
+                        ids.apply_batch(Default::default(), vec![MachineId(0)]);
+                        ports.responses.deliver(LockResponse::Began { key: ids.issued[0] });
+                    }
+                    Some((key, request)) = ports.requests.next() => {
+                        let to = match request.entity() {
+                            Some(entity) => vec![shard_of(entity, num_shards)],
+                            None => (0..num_shards).collect(),
+                        };
+                        for shard in to {
+                            let manager = &mut shards[shard];
+                            manager.apply_batch(vec![LockInput::Request(0, key, request.clone())]);
+                            for (_, response) in manager.outbox.drain(..) {
+                                ports.responses.deliver(response);
+                            }
+                        }
+                    }
+                    else => break,
+                }
+            }
+        })
+    }
+
+    /// The first root owned by `shard` out of 2.
+    fn root_on(shard: usize) -> EntityPath<EntityId> {
+        (0..)
+            .map(|root| EntityPath(vec![EntityId(root)]))
+            .find(|entity| shard_of(entity, 2) == shard)
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn waits_for_its_lock() {
+        let (client, ports) = LockClient::new();
+        let service = serve(ports, 1, LockConfig::default());
+
+        let entity = EntityPath(vec![EntityId(0)]);
+        let first = client.begin().await;
+        let second = client.begin().await;
+        assert!(first < second);
+        assert_eq!(
+            client.acquire(first, entity.clone(), LockMode::X).await,
+            Ok(())
+        );
+        let queues = client.inspect(second, Some(entity.clone())).await.queues;
+        assert_eq!(queues[0].holders, vec![(first, LockMode::X)]);
+
+        // the acquire is sent before the commit, and queues until the commit lets it through
+        let (granted, committed) = tokio::join!(
+            client.acquire(second, entity.clone(), LockMode::S),
+            client.commit(first),
+        );
+        assert_eq!(granted, Ok(()));
+        assert_eq!(committed, Ok(()));
+
+        let third = client.begin().await;
+        let (aborted, ()) =
+            tokio::join!(client.acquire(third, entity.clone(), LockMode::X), async {
+                client.abort(third)
+            },);
+        assert_eq!(
+            aborted,
+            Err(LockResponse::Aborted {
+                key: third,
+                cause: AbortCause::Requested,
+            })
+        );
+
+        drop(client);
+        service.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn aborts_on_every_shard() {
+        let (client, ports) = LockClient::new();
+        let config = LockConfig {
+            prevention: Some(Prevention::WaitDie),
+            ..Default::default()
+        };
+        let service = serve(ports, 2, config);
+
+        let older = client.begin().await;
+        let younger = client.begin().await;
+        assert_eq!(client.acquire(older, root_on(0), LockMode::X).await, Ok(()));
+        assert_eq!(
+            client.acquire(younger, root_on(1), LockMode::X).await,
+            Ok(())
+        );
+
+        // the first shard kills the younger transaction, which the second one never saw wait
+        assert_eq!(
+            client.acquire(younger, root_on(0), LockMode::S).await,
+            Err(LockResponse::Aborted {
+                key: younger,
+                cause: AbortCause::Prevention,
+            })
+        );
+        assert_eq!(
+            client.inspect(older, Some(root_on(1))).await,
+            Inspection::default()
+        );
+
+        drop(client);
+        service.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn stays_shrinking_across_shards() {
+        let (client, ports) = LockClient::new();
+        let service = serve(ports, 2, LockConfig::default());
+
+        let key = client.begin().await;
+        assert_eq!(client.acquire(key, root_on(0), LockMode::S).await, Ok(()));
+        client.release(key, root_on(0));
+        // the second shard never saw the release
+        assert_eq!(
+            client.acquire(key, root_on(1), LockMode::S).await,
+            Err(LockResponse::Denied {
+                key,
+                request: ClientRequest::Acquire {
+                    entity: root_on(1),
+                    mode: LockMode::S,
+                },
+                reason: DenyReason::Shrinking,
+            })
+        );
+        assert_eq!(
+            client.inspect(key, Some(root_on(1))).await,
+            Inspection::default()
+        );
+
+        assert_eq!(client.commit(key).await, Ok(()));
+        let key = client.begin().await;
+        assert_eq!(client.acquire(key, root_on(1), LockMode::S).await, Ok(()));
+
+        drop(client);
+        service.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn inspects_every_shard() {
+        let (client, ports) = LockClient::new();
+        let service = serve(ports, 2, LockConfig::default());
+
+        let key = client.begin().await;
+        assert_eq!(client.acquire(key, root_on(0), LockMode::S).await, Ok(()));
+        assert_eq!(client.acquire(key, root_on(1), LockMode::S).await, Ok(()));
+
+        // both shards answer both inspections, and each inspection gets one answer from each
+        let (first, second) = tokio::join!(client.inspect(key, None), client.inspect(key, None));
+        let mut expected = vec![root_on(0), root_on(1)];
+        expected.sort();
+        for inspection in [first, second] {
+            let entities = inspection
+                .queues
+                .into_iter()
+                .map(|queue| queue.entity)
+                .collect::<Vec<_>>();
+            assert_eq!(entities, expected);
+            assert_eq!(inspection.usage.entities, 2);
+        }
+
+        drop(client);
+        service.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn commits_once_every_shard_answers() {
+        let (client, mut ports) = LockClient::new();
+        let key = |transaction_id| Key {
+            transaction_id: TransactionId(transaction_id),
+            machine_id: MachineId(0),
+        };
+
+        let mut commit = Box::pin(client.commit(key(0)));
+        assert!((&mut commit).now_or_never().is_none());
+        assert_eq!(
+            ports.requests.next().await,
+            Some((key(0), ClientRequest::Commit))
+        );
+        ports.responses.deliver(LockResponse::Committed {
+            key: key(0),
+            shard: 1,
+            shards: 2,
+        });
+        assert!((&mut commit).now_or_never().is_none());
+        ports.responses.deliver(LockResponse::Committed {
+            key: key(0),
+            shard: 0,
+            shards: 2,
+        });
+        assert_eq!(commit.now_or_never(), Some(Ok(())));
+
+        // a shard that gave up on the transaction first ends the commit
+        let mut commit = Box::pin(client.commit(key(1)));
+        assert!((&mut commit).now_or_never().is_none());
+        let aborted = LockResponse::Aborted {
+            key: key(1),
+            cause: AbortCause::LeaseExpired,
+        };
+        ports.responses.deliver(aborted.clone());
+        assert_eq!(commit.now_or_never(), Some(Err(aborted)));
+    }
+
+    #[tokio::test]
+    async fn refuses_second_acquire_of_one_entity() {
+        let (client, ports) = LockClient::new();
+        let service = serve(ports, 1, LockConfig::default());
+
+        let entity = EntityPath(vec![EntityId(0)]);
+        let holder = client.begin().await;
+        let key = client.begin().await;
+        assert_eq!(
+            client.acquire(holder, entity.clone(), LockMode::X).await,
+            Ok(())
+        );
+
+        // the upgrade is asked for while the first acquire still waits
+        let (first, second) =
+            tokio::join!(client.acquire(key, entity.clone(), LockMode::S), async {
+                let second = client.acquire(key, entity.clone(), LockMode::X).await;
+                assert_eq!(client.commit(holder).await, Ok(()));
+                second
+            },);
+        assert_eq!(first, Ok(()));
+        assert_eq!(
+            second,
+            Err(LockResponse::Denied {
+                key,
+                request: ClientRequest::Acquire {
+                    entity: entity.clone(),
+                    mode: LockMode::X,
+                },
+                reason: DenyReason::AlreadyWaiting,
+            })
+        );
+
+        drop(client);
+        service.await.unwrap();
+    }
+}
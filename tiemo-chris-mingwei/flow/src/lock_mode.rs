@@ -0,0 +1,242 @@
+use std::cmp::Ordering;
+
+use lattices::{IsBot, IsTop, LatticeFrom, LatticeOrd, Merge};
+use serde::{Deserialize, Serialize};
+
+/// Multi-granularity lock modes. These form a join-semilattice ordered by strength,
+/// `NL < IS < {IX, S} < SIX < X`, where `IX` and `S` are incomparable and join to `SIX`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum LockMode {
+    NL,
+    IS,
+    IX,
+    S,
+    SIX,
+    X,
+}
+impl LockMode {
+    pub fn compatible(self, other: Self) -> bool {
+        match (self, other) {
+            (LockMode::NL, LockMode::NL) => true,
+            (LockMode::NL, LockMode::IS) => true,
+            (LockMode::NL, LockMode::IX) => true,
+            (LockMode::NL, LockMode::S) => true,
+            (LockMode::NL, LockMode::SIX) => true,
+            (LockMode::NL, LockMode::X) => true,
+            (LockMode::IS, LockMode::NL) => true,
+            (LockMode::IS, LockMode::IS) => true,
+            (LockMode::IS, LockMode::IX) => true,
+            (LockMode::IS, LockMode::S) => true,
+            (LockMode::IS, LockMode::SIX) => true,
+            (LockMode::IS, LockMode::X) => false,
+            (LockMode::IX, LockMode::NL) => true,
+            (LockMode::IX, LockMode::IS) => true,
+            (LockMode::IX, LockMode::IX) => true,
+            (LockMode::IX, LockMode::S) => false,
+            (LockMode::IX, LockMode::SIX) => false,
+            (LockMode::IX, LockMode::X) => false,
+            (LockMode::S, LockMode::NL) => true,
+            (LockMode::S, LockMode::IS) => true,
+            (LockMode::S, LockMode::IX) => false,
+            (LockMode::S, LockMode::S) => true,
+            (LockMode::S, LockMode::SIX) => false,
+            (LockMode::S, LockMode::X) => false,
+            (LockMode::SIX, LockMode::NL) => true,
+            (LockMode::SIX, LockMode::IS) => true,
+            (LockMode::SIX, LockMode::IX) => false,
+            (LockMode::SIX, LockMode::S) => false,
+            (LockMode::SIX, LockMode::SIX) => false,
+            (LockMode::SIX, LockMode::X) => false,
+            (LockMode::X, LockMode::NL) => true,
+            (LockMode::X, LockMode::IS) => false,
+            (LockMode::X, LockMode::IX) => false,
+            (LockMode::X, LockMode::S) => false,
+            (LockMode::X, LockMode::SIX) => false,
+            (LockMode::X, LockMode::X) => false,
+        }
+    }
+
+    /// The intention mode a holder needs on every ancestor of an entity before locking the
+    /// entity itself in this mode.
+    pub fn intention(self) -> LockMode {
+        match self {
+            LockMode::NL => LockMode::NL,
+            LockMode::IS | LockMode::S => LockMode::IS,
+            LockMode::IX | LockMode::SIX | LockMode::X => LockMode::IX,
+        }
+    }
+
+    /// Whether holding `other` implies holding `self`, i.e. `self <= other` in the lattice.
+    fn covered_by(self, other: Self) -> bool {
+        match (self, other) {
+            (LockMode::NL, _) => true,
+            (LockMode::IS, LockMode::NL) => false,
+            (LockMode::IS, _) => true,
+            (LockMode::IX, LockMode::IX | LockMode::SIX | LockMode::X) => true,
+            (LockMode::S, LockMode::S | LockMode::SIX | LockMode::X) => true,
+            (LockMode::SIX, LockMode::SIX | LockMode::X) => true,
+            (LockMode::X, LockMode::X) => true,
+            _ => false,
+        }
+    }
+}
+
+impl PartialOrd for LockMode {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        match (self.covered_by(*other), other.covered_by(*self)) {
+            (true, true) => Some(Ordering::Equal),
+            (true, false) => Some(Ordering::Less),
+            (false, true) => Some(Ordering::Greater),
+            (false, false) => None,
+        }
+    }
+}
+
+impl LatticeOrd for LockMode {}
+
+impl Merge<LockMode> for LockMode {
+    fn merge(&mut self, other: LockMode) -> bool {
+        let joined = match (*self).partial_cmp(&other) {
+            Some(Ordering::Less) => other,
+            Some(_) => *self,
+            // IX and S are the only incomparable pair
+            None => LockMode::SIX,
+        };
+        let changed = joined != *self;
+        *self = joined;
+        changed
+    }
+}
+
+impl LatticeFrom<LockMode> for LockMode {
+    fn lattice_from(other: LockMode) -> Self {
+        other
+    }
+}
+
+impl IsBot for LockMode {
+    fn is_bot(&self) -> bool {
+        *self == LockMode::NL
+    }
+}
+
+impl IsTop for LockMode {
+    fn is_top(&self) -> bool {
+        *self == LockMode::X
+    }
+}
+
+#[stageleft::runtime]
+#[cfg(test)]
+mod tests {
+    use lattices::Merge;
+
+    use super::LockMode;
+
+    const ALL: [LockMode; 6] = [
+        LockMode::NL,
+        LockMode::IS,
+        LockMode::IX,
+        LockMode::S,
+        LockMode::SIX,
+        LockMode::X,
+    ];
+
+    fn join(a: LockMode, b: LockMode) -> LockMode {
+        Merge::merge_owned(a, b)
+    }
+
+    #[test]
+    fn merge_is_least_upper_bound() {
+        for a in ALL {
+            for b in ALL {
+                let sup = join(a, b);
+                assert!(a <= sup && b <= sup, "{:?} ⊔ {:?} = {:?}", a, b, sup);
+                assert_eq!(sup, join(b, a));
+                for c in ALL {
+                    if a <= c && b <= c {
+                        assert!(sup <= c, "{:?} ⊔ {:?} = {:?} > {:?}", a, b, sup, c);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn merge_reports_changes() {
+        for a in ALL {
+            for b in ALL {
+                let mut merged = a;
+                assert_eq!(merged.merge(b), join(a, b) != a);
+            }
+        }
+    }
+
+    #[test]
+    fn known_suprema() {
+        assert_eq!(join(LockMode::IS, LockMode::IX), LockMode::IX);
+        assert_eq!(join(LockMode::S, LockMode::IX), LockMode::SIX);
+        assert_eq!(join(LockMode::IS, LockMode::S), LockMode::S);
+        assert_eq!(join(LockMode::SIX, LockMode::IS), LockMode::SIX);
+        assert_eq!(join(LockMode::NL, LockMode::X), LockMode::X);
+    }
+
+    #[test]
+    fn compatible_is_symmetric() {
+        for a in ALL {
+            for b in ALL {
+                assert_eq!(a.compatible(b), b.compatible(a), "{:?} vs {:?}", a, b);
+            }
+        }
+    }
+
+    #[test]
+    fn nl_is_compatible_with_everything() {
+        for a in ALL {
+            assert!(LockMode::NL.compatible(a) && a.compatible(LockMode::NL));
+        }
+    }
+
+    #[test]
+    fn weaker_modes_are_more_compatible() {
+        for a in ALL {
+            for b in ALL {
+                for c in ALL {
+                    if a <= b && b.compatible(c) {
+                        assert!(
+                            a.compatible(c),
+                            "{:?} <= {:?} but only {:?} is compatible with {:?}",
+                            a,
+                            b,
+                            b,
+                            c
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    /// Checking a request against the join of two compatible holders gives the same answer as
+    /// checking it against each holder, so the group mode can stand in for the whole holder set.
+    #[test]
+    fn group_mode_matches_pairwise_check() {
+        for a in ALL {
+            for b in ALL {
+                if !a.compatible(b) {
+                    continue;
+                }
+                for request in ALL {
+                    assert_eq!(
+                        join(a, b).compatible(request),
+                        a.compatible(request) && b.compatible(request),
+                        "holders {:?} and {:?}, request {:?}",
+                        a,
+                        b,
+                        request
+                    );
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,959 @@
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::mem::size_of;
+use std::ops::AddAssign;
+
+use lattices::Merge;
+use serde::{Deserialize, Serialize};
+
+use crate::lock_mode::LockMode;
+
+/// The order in which a queue grants the requests waiting on it once they are compatible with its
+/// holders. Pending conversions are always granted first, in arrival order.
+///
+/// Policies that let requests go ahead of others count how often each waiter was passed over by
+/// one that arrived after it. A waiter passed over `max_bypass` times has starved, and is served
+/// before anything behind it, so a larger `max_bypass` trades fairness for throughput.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum Fairness {
+    /// Requests are granted in arrival order, and one that is blocked holds up everything behind
+    /// it.
+    #[default]
+    Fifo,
+    /// Requests are granted in arrival order, but compatible ones go past a blocked one unless it
+    /// has starved.
+    CompatibleJump { max_bypass: u32 },
+    /// Requests are granted lowest holder first, which for transaction keys is oldest first, and
+    /// one that is blocked holds up everything behind it. Starved requests go before all others,
+    /// in arrival order.
+    Priority { max_bypass: u32 },
+}
+
+/// What happened to a lock request.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AcquireOutcome {
+    /// The request was granted, possibly by converting the holder's existing grant in place.
+    Granted,
+    /// The request is queued and will show up in a later [`LockQueue::grant_waiters`].
+    Waiting,
+    /// The holder already has a request waiting on this lock.
+    AlreadyWaiting,
+    /// The holder's conversion would wait on other pending conversions that in turn wait on it,
+    /// so the request was refused instead of queued.
+    ConversionDeadlock,
+}
+
+/// Lock state of a single entity: the holders that were granted it and the requests queued
+/// behind them, in arrival order.
+///
+/// Every request is indexed by its holder, and queued ones also by arrival and by mode, so that
+/// granting, releasing and withdrawing a request takes time logarithmic in the length of the
+/// queue, plus the requests it grants.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(bound(deserialize = "H: Ord + Deserialize<'de>"))]
+pub struct LockQueue<H> {
+    fairness: Fairness,
+    /// In no particular order, since releases swap the last holder into the freed slot.
+    holders: Vec<(H, LockMode)>,
+    /// The position of each holder in `holders`.
+    held: BTreeMap<H, usize>,
+    /// How many holders hold each mode, indexed by [`slot`], so that the group mode can be
+    /// recomputed without visiting every holder.
+    held_modes: [usize; MODES.len()],
+    /// The combined mode of every holder. New requests are checked against this rather than
+    /// against whichever holder happened to be granted last.
+    group_mode: LockMode,
+    /// Holders waiting to convert their grant to a stronger mode. These are served before any
+    /// new waiter, since the holder already blocks the waiters anyway.
+    conversions: VecDeque<(H, LockMode)>,
+    /// Queued requests by arrival ticket.
+    waiters: BTreeMap<u64, (H, LockMode)>,
+    /// The ticket of each queued holder, which also lists them lowest holder first.
+    waiting: BTreeMap<H, u64>,
+    /// The tickets of the queued requests for each mode, indexed by [`slot`].
+    waiting_modes: [BTreeSet<u64>; MODES.len()],
+    next_ticket: u64,
+    /// The tickets of queued requests that were granted while an older one kept waiting. A waiter
+    /// was passed over once for every one of these that is younger than itself, so only tickets
+    /// younger than the oldest waiter are kept.
+    jumped: BTreeSet<u64>,
+}
+
+/// Every lock mode, in the order [`slot`] numbers them.
+const MODES: [LockMode; 6] = [
+    LockMode::NL,
+    LockMode::IS,
+    LockMode::IX,
+    LockMode::S,
+    LockMode::SIX,
+    LockMode::X,
+];
+
+fn slot(mode: LockMode) -> usize {
+    mode as usize
+}
+
+/// The join of every mode that is held at least once.
+fn joined(counts: &[usize; MODES.len()]) -> LockMode {
+    MODES
+        .into_iter()
+        .filter(|mode| counts[slot(*mode)] > 0)
+        .fold(LockMode::NL, Merge::merge_owned)
+}
+
+impl<H> Default for LockQueue<H> {
+    fn default() -> Self {
+        Self::new(Fairness::default())
+    }
+}
+
+impl<H> LockQueue<H> {
+    pub fn new(fairness: Fairness) -> Self {
+        Self {
+            fairness,
+            holders: Vec::new(),
+            held: BTreeMap::new(),
+            held_modes: [0; MODES.len()],
+            group_mode: LockMode::NL,
+            conversions: VecDeque::new(),
+            waiters: BTreeMap::new(),
+            waiting: BTreeMap::new(),
+            waiting_modes: Default::default(),
+            next_ticket: 0,
+            jumped: BTreeSet::new(),
+        }
+    }
+}
+
+impl<H: Clone + Ord> LockQueue<H> {
+    pub fn holders(&self) -> &[(H, LockMode)] {
+        &self.holders
+    }
+
+    /// Pending conversions, as the mode each holder is converting to.
+    pub fn conversions(&self) -> &VecDeque<(H, LockMode)> {
+        &self.conversions
+    }
+
+    /// Queued requests, in arrival order.
+    pub fn waiters(&self) -> impl ExactSizeIterator<Item = &(H, LockMode)> {
+        self.waiters.values()
+    }
+
+    pub fn group_mode(&self) -> LockMode {
+        self.group_mode
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.holders.is_empty() && self.waiters.is_empty()
+    }
+
+    /// Whether `holder` is holding or waiting for this lock.
+    pub fn contains(&self, holder: &H) -> bool {
+        self.held.contains_key(holder) || self.waiting.contains_key(holder)
+    }
+
+    pub fn held_mode(&self, holder: &H) -> Option<LockMode> {
+        self.held.get(holder).map(|i| self.holders[*i].1)
+    }
+
+    /// Requests `mode` for `holder`.
+    ///
+    /// A new request is granted right away if it is compatible with the group mode and the
+    /// [`Fairness`] policy lets it go ahead of whatever is queued, otherwise it waits. A request
+    /// from a current holder converts its grant to the join of the held and requested modes, which
+    /// happens in place as soon as the other holders allow it.
+    pub fn acquire(&mut self, holder: H, mode: LockMode) -> AcquireOutcome {
+        if let Some(held) = self.held_mode(&holder) {
+            return self.convert(holder, held, mode);
+        }
+        if self.waiting.contains_key(&holder) {
+            return AcquireOutcome::AlreadyWaiting;
+        }
+
+        let ticket = self.enqueue(holder.clone(), mode);
+        // Only the new request is granted here. Going ahead of the others can starve one that fits
+        // the group mode, but that one is left to the next `grant_waiters`, which reports it.
+        let oldest = *self.waiters.keys().next().unwrap();
+        let first = match self.fairness {
+            _ if oldest == ticket => true,
+            Fairness::Fifo => false,
+            // the oldest waiter has been passed over the most, so nobody starved unless it did
+            Fairness::CompatibleJump { max_bypass } => self.jumped.len() < max_bypass as usize,
+            Fairness::Priority { max_bypass } => {
+                self.jumped.len() < max_bypass as usize
+                    && self.waiting.keys().next() == Some(&holder)
+            }
+        };
+        if self.conversions.is_empty() && first && self.group_mode.compatible(mode) {
+            self.grant_waiter(ticket);
+            AcquireOutcome::Granted
+        } else {
+            AcquireOutcome::Waiting
+        }
+    }
+
+    fn convert(&mut self, holder: H, held: LockMode, mode: LockMode) -> AcquireOutcome {
+        if self.conversions.iter().any(|(h, _)| *h == holder) {
+            return AcquireOutcome::AlreadyWaiting;
+        }
+
+        let target = Merge::merge_owned(held, mode);
+        if target == held {
+            AcquireOutcome::Granted
+        } else if self.conversions.is_empty() && self.others_mode(&holder).compatible(target) {
+            self.upgrade(&holder, target);
+            AcquireOutcome::Granted
+        } else if self.conversion_cycle(&holder, target) {
+            AcquireOutcome::ConversionDeadlock
+        } else {
+            self.conversions.push_back((holder, target));
+            AcquireOutcome::Waiting
+        }
+    }
+
+    /// Whether [`LockQueue::acquire`] would grant `mode` to `holder` right away, found without
+    /// queueing anything.
+    pub fn would_grant(&self, holder: &H, mode: LockMode) -> bool {
+        if let Some(held) = self.held_mode(holder) {
+            return !self.conversions.iter().any(|(h, _)| h == holder)
+                && (Merge::merge_owned(held, mode) == held || self.can_convert(holder, mode));
+        }
+        if self.waiting.contains_key(holder) {
+            return false;
+        }
+
+        // the request would get the youngest ticket, so it is the oldest only in an empty queue
+        let first = match self.fairness {
+            _ if self.waiters.is_empty() => true,
+            Fairness::Fifo => false,
+            Fairness::CompatibleJump { max_bypass } => self.jumped.len() < max_bypass as usize,
+            Fairness::Priority { max_bypass } => {
+                self.jumped.len() < max_bypass as usize
+                    && self
+                        .waiting
+                        .keys()
+                        .next()
+                        .is_some_and(|lowest| holder < lowest)
+            }
+        };
+        self.conversions.is_empty() && first && self.group_mode.compatible(mode)
+    }
+
+    /// The refusal [`LockQueue::acquire`] would answer `mode` for `holder` with, if any, found
+    /// without queueing anything.
+    pub fn refusal(&self, holder: &H, mode: LockMode) -> Option<AcquireOutcome> {
+        match self.held_mode(holder) {
+            Some(_) if self.conversions.iter().any(|(h, _)| h == holder) => {
+                Some(AcquireOutcome::AlreadyWaiting)
+            }
+            Some(held) => {
+                let target = Merge::merge_owned(held, mode);
+                let deadlocked = target != held
+                    && !self.can_convert(holder, mode)
+                    && self.conversion_cycle(holder, target);
+                deadlocked.then_some(AcquireOutcome::ConversionDeadlock)
+            }
+            None if self.waiting.contains_key(holder) => Some(AcquireOutcome::AlreadyWaiting),
+            None => None,
+        }
+    }
+
+    /// Whether converting `holder`'s grant to include `mode` would be granted right away, rather
+    /// than queued. Always false for a holder without a grant.
+    pub fn can_convert(&self, holder: &H, mode: LockMode) -> bool {
+        match self.held_mode(holder) {
+            Some(held) => {
+                self.conversions.is_empty()
+                    && self
+                        .others_mode(holder)
+                        .compatible(Merge::merge_owned(held, mode))
+            }
+            None => false,
+        }
+    }
+
+    /// Drops the grant and any queued request of `holder`, returning whether it had one. Call
+    /// [`LockQueue::grant_waiters`] afterwards to hand the lock on.
+    pub fn remove(&mut self, holder: &H) -> bool {
+        let released = self.ungrant(holder);
+        let cancelled = self.cancel(holder);
+        if !released && !cancelled {
+            return false;
+        }
+
+        self.group_mode = joined(&self.held_modes);
+        true
+    }
+
+    /// Weakens `holder`'s grant to `mode`, undoing a conversion it no longer needs. Call
+    /// [`LockQueue::grant_waiters`] afterwards, since the weaker grant may let others through.
+    pub fn downgrade(&mut self, holder: &H, mode: LockMode) {
+        let held = &mut self.holders[self.held[holder]].1;
+        self.held_modes[slot(*held)] -= 1;
+        self.held_modes[slot(mode)] += 1;
+        *held = mode;
+        self.group_mode = joined(&self.held_modes);
+    }
+
+    /// Withdraws the pending conversion or queued request of `holder` but keeps any grant it
+    /// already has, returning whether it had one. Call [`LockQueue::grant_waiters`] afterwards,
+    /// since whoever queued behind it may be able to go now.
+    pub fn cancel(&mut self, holder: &H) -> bool {
+        let converting = self.conversions.len();
+        self.conversions.retain(|(h, _)| h != holder);
+        match self.waiting.get(holder).copied() {
+            Some(ticket) => {
+                self.dequeue(ticket);
+                true
+            }
+            None => converting != self.conversions.len(),
+        }
+    }
+
+    /// Grants pending conversions in FIFO order until the first one that is blocked, and then
+    /// queued requests in the order of the [`Fairness`] policy. Returns the new grants, with the
+    /// converted mode for conversions.
+    pub fn grant_waiters(&mut self) -> Vec<(H, LockMode)> {
+        let mut granted = Vec::new();
+        while let Some((holder, target)) = self.conversions.front() {
+            if !self.others_mode(holder).compatible(*target) {
+                return granted;
+            }
+            let (holder, target) = self.conversions.pop_front().unwrap();
+            self.upgrade(&holder, target);
+            granted.push((holder, target));
+        }
+        granted.extend(self.grant_queued());
+        granted
+    }
+
+    /// Queued requests in the order they are served, each with whether it holds up everything
+    /// served after it while it is blocked.
+    pub fn queued(&self) -> Vec<(&H, LockMode, bool)> {
+        let starved = self.starved();
+        let rest = starved.last().map_or(0, |ticket| ticket + 1);
+        let rest = match self.fairness {
+            Fairness::Priority { .. } => self
+                .waiting
+                .values()
+                .filter(|ticket| **ticket >= rest)
+                .copied()
+                .collect::<Vec<_>>(),
+            _ => self
+                .waiters
+                .range(rest..)
+                .map(|(ticket, _)| *ticket)
+                .collect(),
+        };
+        let holds_up = !matches!(self.fairness, Fairness::CompatibleJump { .. });
+        starved
+            .iter()
+            .map(|ticket| (ticket, true))
+            .chain(rest.iter().map(|ticket| (ticket, holds_up)))
+            .map(|(ticket, holds_up)| {
+                let (holder, mode) = &self.waiters[ticket];
+                (holder, *mode, holds_up)
+            })
+            .collect()
+    }
+
+    /// The tickets of the waiters that were passed over `max_bypass` times, oldest first. A waiter
+    /// is passed over by every grant that passes over a younger one, so these are always the
+    /// oldest waiters of the queue.
+    fn starved(&self) -> Vec<u64> {
+        let max_bypass = match self.fairness {
+            Fairness::Fifo => return Vec::new(),
+            Fairness::CompatibleJump { max_bypass } | Fairness::Priority { max_bypass } => {
+                max_bypass as usize
+            }
+        };
+        let mut bypassed = self.jumped.len();
+        let mut jumped = self.jumped.iter().peekable();
+        let mut starved = Vec::new();
+        for ticket in self.waiters.keys() {
+            while jumped.next_if(|jumped| *jumped <= ticket).is_some() {
+                bypassed -= 1;
+            }
+            if bypassed < max_bypass {
+                break;
+            }
+            starved.push(*ticket);
+        }
+        starved
+    }
+
+    /// The ticket of the oldest waiter if it starved. It has been passed over by every grant in
+    /// `jumped`, and if it did not starve then nobody did.
+    fn oldest_starved(&self) -> Option<u64> {
+        match self.fairness {
+            Fairness::Fifo => None,
+            Fairness::CompatibleJump { max_bypass } | Fairness::Priority { max_bypass } => {
+                if self.jumped.len() < max_bypass as usize {
+                    return None;
+                }
+                self.waiters.keys().next().copied()
+            }
+        }
+    }
+
+    /// Grants queued requests in service order, stopping at a blocked one that holds up the rest.
+    /// Passing a waiter over can starve it and so move it ahead, which may let it through too, so
+    /// this goes on until a round grants nothing.
+    fn grant_queued(&mut self) -> Vec<(H, LockMode)> {
+        let mut granted = Vec::new();
+        loop {
+            let before = granted.len();
+            // starved requests hold up everything behind them under every policy
+            while let Some(ticket) = self.oldest_starved() {
+                if !self.group_mode.compatible(self.waiters[&ticket].1) {
+                    return granted;
+                }
+                granted.push(self.grant_waiter(ticket));
+            }
+            while let Some(ticket) = self.next_grantable() {
+                granted.push(self.grant_waiter(ticket));
+            }
+            if granted.len() == before {
+                return granted;
+            }
+        }
+    }
+
+    /// The next queued request to grant once no starved one is left, if any.
+    fn next_grantable(&self) -> Option<u64> {
+        let ticket = match self.fairness {
+            Fairness::Fifo => *self.waiters.keys().next()?,
+            Fairness::Priority { .. } => *self.waiting.values().next()?,
+            // blocked requests do not hold up the rest, so this is the oldest one of any mode
+            // that fits
+            Fairness::CompatibleJump { .. } => {
+                return MODES
+                    .into_iter()
+                    .filter(|mode| self.group_mode.compatible(*mode))
+                    .filter_map(|mode| self.waiting_modes[slot(mode)].first())
+                    .min()
+                    .copied();
+            }
+        };
+        self.group_mode
+            .compatible(self.waiters[&ticket].1)
+            .then_some(ticket)
+    }
+
+    fn enqueue(&mut self, holder: H, mode: LockMode) -> u64 {
+        let ticket = self.next_ticket;
+        self.next_ticket += 1;
+        self.waiting.insert(holder.clone(), ticket);
+        self.waiting_modes[slot(mode)].insert(ticket);
+        self.waiters.insert(ticket, (holder, mode));
+        ticket
+    }
+
+    fn dequeue(&mut self, ticket: u64) -> (H, LockMode) {
+        let (holder, mode) = self.waiters.remove(&ticket).unwrap();
+        self.waiting.remove(&holder);
+        self.waiting_modes[slot(mode)].remove(&ticket);
+        // grants older than every waiter did not pass anyone over that is still here
+        let oldest = self.waiters.keys().next().copied().unwrap_or(u64::MAX);
+        while self.jumped.first().is_some_and(|jumped| *jumped <= oldest) {
+            self.jumped.pop_first();
+        }
+        (holder, mode)
+    }
+
+    /// Grants the queued request with `ticket`, which passes over every older one.
+    fn grant_waiter(&mut self, ticket: u64) -> (H, LockMode) {
+        let (holder, mode) = self.dequeue(ticket);
+        if self.waiters.range(..ticket).next().is_some() {
+            self.jumped.insert(ticket);
+        }
+        self.held.insert(holder.clone(), self.holders.len());
+        self.holders.push((holder.clone(), mode));
+        self.held_modes[slot(mode)] += 1;
+        self.group_mode.merge(mode);
+        (holder, mode)
+    }
+
+    /// Drops the grant of `holder`, if it has one, without updating the group mode.
+    fn ungrant(&mut self, holder: &H) -> bool {
+        let Some(i) = self.held.remove(holder) else {
+            return false;
+        };
+        let (_, mode) = self.holders.swap_remove(i);
+        if let Some((moved, _)) = self.holders.get(i) {
+            self.held.insert(moved.clone(), i);
+        }
+        self.held_modes[slot(mode)] -= 1;
+        true
+    }
+
+    /// The combined mode of every holder except `holder`.
+    fn others_mode(&self, holder: &H) -> LockMode {
+        let mut counts = self.held_modes;
+        if let Some(mode) = self.held_mode(holder) {
+            counts[slot(mode)] -= 1;
+        }
+        joined(&counts)
+    }
+
+    fn upgrade(&mut self, holder: &H, target: LockMode) {
+        let mode = &mut self.holders[self.held[holder]].1;
+        self.held_modes[slot(*mode)] -= 1;
+        self.held_modes[slot(target)] += 1;
+        *mode = target;
+        self.group_mode.merge(target);
+    }
+
+    /// Whether queueing `holder`'s conversion to `target` closes a cycle among pending
+    /// conversions, e.g. two S holders that both ask for X. A conversion waits on every other
+    /// holder whose held mode conflicts with its target.
+    fn conversion_cycle(&self, holder: &H, target: LockMode) -> bool {
+        let blocks = |converter: &H, converter_target: LockMode, other: &H| {
+            converter != other
+                && self
+                    .held_mode(other)
+                    .is_some_and(|held| !held.compatible(converter_target))
+        };
+
+        let mut visited = Vec::<&H>::new();
+        let mut stack = self
+            .conversions
+            .iter()
+            .filter(|(other, _)| blocks(holder, target, other))
+            .collect::<Vec<_>>();
+        while let Some((converter, converter_target)) = stack.pop() {
+            if blocks(converter, *converter_target, holder) {
+                return true;
+            }
+            if visited.contains(&converter) {
+                continue;
+            }
+            visited.push(converter);
+            stack.extend(
+                self.conversions
+                    .iter()
+                    .filter(|(other, _)| blocks(converter, *converter_target, other)),
+            );
+        }
+        false
+    }
+}
+
+/// How much a [`LockTable`] keeps track of, to keep its memory in check.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct TableUsage {
+    /// Entities with a queue. Every queue has a holder or a waiter, since empty ones are dropped.
+    pub entities: usize,
+    /// Grants, pending conversions and queued requests, across all queues.
+    pub requests: usize,
+    /// A rough estimate of the memory the table takes, leaving out spare capacity and allocator
+    /// overhead.
+    pub bytes: usize,
+}
+
+impl AddAssign for TableUsage {
+    /// Adds up the usage of several tables, e.g. the shards of one service.
+    fn add_assign(&mut self, other: Self) {
+        self.entities += other.entities;
+        self.requests += other.requests;
+        self.bytes += other.bytes;
+    }
+}
+
+/// A lock queue per entity, for any entity id `E` and holder id `H`. The queue of an entity is
+/// dropped as soon as nobody holds or waits for it, so the table only grows with the locks in use.
+///
+/// Every collection is ordered, so that two tables given the same calls visit entities and grant
+/// waiters in the same order, as replicas and replays of the log rely on.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(bound(
+    serialize = "E: Serialize, H: Serialize",
+    deserialize = "E: Ord + Deserialize<'de>, H: Ord + Deserialize<'de>"
+))]
+pub struct LockTable<E, H> {
+    /// The policy every queue of this table grants by.
+    fairness: Fairness,
+    queues: BTreeMap<E, LockQueue<H>>,
+    /// The entities each holder holds or waits for, so that releasing everything of one holder
+    /// does not have to visit every queue.
+    by_holder: BTreeMap<H, BTreeSet<E>>,
+    /// The entities whose queue changed since the last [`LockTable::take_changed`], so that checks
+    /// over the waits in the table only have to look at those.
+    changed: BTreeSet<E>,
+}
+
+impl<E, H> Default for LockTable<E, H> {
+    fn default() -> Self {
+        Self::new(Fairness::default())
+    }
+}
+
+impl<E, H> LockTable<E, H> {
+    pub fn new(fairness: Fairness) -> Self {
+        Self {
+            fairness,
+            queues: BTreeMap::new(),
+            by_holder: BTreeMap::new(),
+            changed: BTreeSet::new(),
+        }
+    }
+}
+
+impl<E: Clone + Ord, H: Clone + Ord> LockTable<E, H> {
+    pub fn queue(&self, entity: &E) -> Option<&LockQueue<H>> {
+        self.queues.get(entity)
+    }
+
+    pub fn queues(&self) -> impl Iterator<Item = (&E, &LockQueue<H>)> {
+        self.queues.iter()
+    }
+
+    /// The number of entities with a queue.
+    pub fn entities(&self) -> usize {
+        self.queues.len()
+    }
+
+    /// Every holder that holds or waits for anything in this table.
+    pub fn involved(&self) -> impl Iterator<Item = &H> {
+        self.by_holder.keys()
+    }
+
+    /// The entities whose queue changed since the last call, including ones whose queue has been
+    /// dropped since.
+    pub fn take_changed(&mut self) -> BTreeSet<E> {
+        std::mem::take(&mut self.changed)
+    }
+
+    /// What the table keeps track of right now, see [`TableUsage`].
+    pub fn usage(&self) -> TableUsage {
+        let requests = self
+            .queues
+            .values()
+            .map(|queue| queue.holders.len() + queue.conversions.len() + queue.waiters.len())
+            .sum::<usize>();
+        let per_entity = size_of::<E>() + size_of::<LockQueue<H>>();
+        // every request is indexed by holder in its queue and in the table, and queued ones also
+        // by mode
+        let per_request =
+            size_of::<(H, LockMode)>() + size_of::<(H, u64)>() + size_of::<u64>() + size_of::<E>();
+        TableUsage {
+            entities: self.queues.len(),
+            requests,
+            bytes: self.queues.len() * per_entity
+                + self.by_holder.len() * size_of::<(H, BTreeSet<E>)>()
+                + requests * per_request,
+        }
+    }
+
+    pub fn contains(&self, entity: &E, holder: &H) -> bool {
+        self.queues
+            .get(entity)
+            .is_some_and(|queue| queue.contains(holder))
+    }
+
+    /// Whether `holder` holds or waits for anything in this table.
+    pub fn involves(&self, holder: &H) -> bool {
+        self.by_holder.contains_key(holder)
+    }
+
+    /// The entities `holder` holds or waits for.
+    pub fn entities_of(&self, holder: &H) -> impl Iterator<Item = &E> {
+        self.by_holder.get(holder).into_iter().flatten()
+    }
+
+    /// The number of entities `holder` has been granted a lock on.
+    pub fn locks_held(&self, holder: &H) -> usize {
+        self.entities_of(holder)
+            .filter(|entity| self.queues[*entity].held_mode(holder).is_some())
+            .count()
+    }
+
+    /// See [`LockQueue::would_grant`]. A lock nobody holds or waits for is always granted.
+    pub fn would_grant(&self, entity: &E, holder: &H, mode: LockMode) -> bool {
+        match self.queues.get(entity) {
+            Some(queue) => queue.would_grant(holder, mode),
+            None => true,
+        }
+    }
+
+    /// See [`LockQueue::refusal`]. A lock nobody holds or waits for is never refused.
+    pub fn refusal(&self, entity: &E, holder: &H, mode: LockMode) -> Option<AcquireOutcome> {
+        self.queues.get(entity)?.refusal(holder, mode)
+    }
+
+    /// See [`LockQueue::acquire`].
+    pub fn acquire(&mut self, entity: E, holder: H, mode: LockMode) -> AcquireOutcome {
+        let fairness = self.fairness;
+        let queue = self
+            .queues
+            .entry(entity.clone())
+            .or_insert_with(|| LockQueue::new(fairness));
+        let outcome = queue.acquire(holder.clone(), mode);
+        if queue.contains(&holder) {
+            self.by_holder
+                .entry(holder)
+                .or_default()
+                .insert(entity.clone());
+        }
+        self.changed.insert(entity);
+        outcome
+    }
+
+    /// Releases the lock `holder` has on `entity`, or withdraws its queued request. Returns `None`
+    /// if it had neither, otherwise the waiters that were granted as a result.
+    pub fn release(&mut self, entity: &E, holder: &H) -> Option<Vec<(E, H, LockMode)>> {
+        let queue = self.queues.get_mut(entity)?;
+        if !queue.remove(holder) {
+            return None;
+        }
+        let granted = queue.grant_waiters();
+        self.unindex(entity, holder);
+        self.evict_if_empty(entity);
+        self.changed.insert(entity.clone());
+        Some(
+            granted
+                .into_iter()
+                .map(|(h, mode)| (entity.clone(), h, mode))
+                .collect(),
+        )
+    }
+
+    /// Withdraws the queued request `holder` has on `entity`, keeping its grant if it is
+    /// converting. Returns `None` if it was not waiting, otherwise the waiters that were granted as
+    /// a result.
+    pub fn cancel(&mut self, entity: &E, holder: &H) -> Option<Vec<(E, H, LockMode)>> {
+        let queue = self.queues.get_mut(entity)?;
+        if !queue.cancel(holder) {
+            return None;
+        }
+        let granted = queue.grant_waiters();
+        self.unindex(entity, holder);
+        self.evict_if_empty(entity);
+        self.changed.insert(entity.clone());
+        Some(
+            granted
+                .into_iter()
+                .map(|(h, mode)| (entity.clone(), h, mode))
+                .collect(),
+        )
+    }
+
+    /// Weakens the grant `holder` has on `entity` to `mode`, returning the waiters that were
+    /// granted as a result.
+    pub fn downgrade(&mut self, entity: &E, holder: &H, mode: LockMode) -> Vec<(E, H, LockMode)> {
+        let queue = self.queues.get_mut(entity).unwrap();
+        queue.downgrade(holder, mode);
+        let granted = queue.grant_waiters();
+        self.changed.insert(entity.clone());
+        granted
+            .into_iter()
+            .map(|(h, mode)| (entity.clone(), h, mode))
+            .collect()
+    }
+
+    /// Releases every lock `holder` holds or waits for, returning the grants this causes.
+    pub fn release_all(&mut self, holder: &H) -> Vec<(E, H, LockMode)> {
+        let mut granted = Vec::new();
+        for entity in self.by_holder.remove(holder).unwrap_or_default() {
+            let queue = self.queues.get_mut(&entity).unwrap();
+            queue.remove(holder);
+            granted.extend(
+                queue
+                    .grant_waiters()
+                    .into_iter()
+                    .map(|(h, mode)| (entity.clone(), h, mode)),
+            );
+            self.evict_if_empty(&entity);
+            self.changed.insert(entity);
+        }
+        granted
+    }
+
+    fn evict_if_empty(&mut self, entity: &E) {
+        if self.queues[entity].is_empty() {
+            self.queues.remove(entity);
+        }
+    }
+
+    /// Drops `entity` from `holder`'s index entry once the holder is gone from its queue.
+    fn unindex(&mut self, entity: &E, holder: &H) {
+        if self.queues[entity].contains(holder) {
+            return;
+        }
+        if let Some(entities) = self.by_holder.get_mut(holder) {
+            entities.remove(entity);
+            if entities.is_empty() {
+                self.by_holder.remove(holder);
+            }
+        }
+    }
+}
+
+#[stageleft::runtime]
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeSet;
+
+    use super::{AcquireOutcome, Fairness, LockQueue, LockTable, TableUsage};
+    use crate::lock_mode::LockMode;
+
+    #[test]
+    fn grants_against_group_mode() {
+        let mut queue = LockQueue::default();
+        assert_eq!(queue.acquire("joe", LockMode::S), AcquireOutcome::Granted);
+        assert_eq!(
+            queue.acquire("shadaj", LockMode::IS),
+            AcquireOutcome::Granted
+        );
+        assert_eq!(queue.group_mode(), LockMode::S);
+
+        // IX conflicts with joe's S even though the last grant was IS
+        assert_eq!(
+            queue.acquire("mingwei", LockMode::IX),
+            AcquireOutcome::Waiting
+        );
+
+        queue.remove(&"joe");
+        assert_eq!(queue.grant_waiters(), vec![("mingwei", LockMode::IX)]);
+        assert_eq!(queue.group_mode(), LockMode::IX);
+    }
+
+    #[test]
+    fn converts_in_place() {
+        let mut queue = LockQueue::default();
+        assert_eq!(queue.acquire("joe", LockMode::IS), AcquireOutcome::Granted);
+        assert_eq!(queue.acquire("joe", LockMode::X), AcquireOutcome::Granted);
+        assert_eq!(queue.holders(), &[("joe", LockMode::X)]);
+
+        // asking for a mode the holder already covers changes nothing
+        assert_eq!(queue.acquire("joe", LockMode::S), AcquireOutcome::Granted);
+        assert_eq!(queue.holders(), &[("joe", LockMode::X)]);
+    }
+
+    #[test]
+    fn conversions_jump_ahead_of_waiters() {
+        let mut queue = LockQueue::default();
+        queue.acquire("joe", LockMode::S);
+        queue.acquire("shadaj", LockMode::S);
+        assert_eq!(
+            queue.acquire("mingwei", LockMode::X),
+            AcquireOutcome::Waiting
+        );
+        assert_eq!(queue.acquire("joe", LockMode::X), AcquireOutcome::Waiting);
+
+        queue.remove(&"shadaj");
+        assert_eq!(queue.grant_waiters(), vec![("joe", LockMode::X)]);
+        assert_eq!(queue.holders(), &[("joe", LockMode::X)]);
+        assert_eq!(queue.waiters().len(), 1);
+    }
+
+    #[test]
+    fn detects_conversion_deadlock() {
+        let mut queue = LockQueue::default();
+        queue.acquire("joe", LockMode::S);
+        queue.acquire("shadaj", LockMode::S);
+        assert_eq!(queue.acquire("joe", LockMode::X), AcquireOutcome::Waiting);
+        assert_eq!(
+            queue.acquire("shadaj", LockMode::X),
+            AcquireOutcome::ConversionDeadlock
+        );
+
+        // shadaj backing off lets joe's conversion through
+        queue.remove(&"shadaj");
+        assert_eq!(queue.grant_waiters(), vec![("joe", LockMode::X)]);
+    }
+
+    #[test]
+    fn compatible_requests_jump_until_starved() {
+        let mut queue = LockQueue::new(Fairness::CompatibleJump { max_bypass: 1 });
+        queue.acquire("joe", LockMode::S);
+        assert_eq!(
+            queue.acquire("mingwei", LockMode::X),
+            AcquireOutcome::Waiting
+        );
+        assert_eq!(
+            queue.acquire("shadaj", LockMode::S),
+            AcquireOutcome::Granted
+        );
+
+        // mingwei was passed over once, so nobody else gets past it
+        assert_eq!(queue.acquire("chris", LockMode::S), AcquireOutcome::Waiting);
+        assert_eq!(queue.queued()[0], (&"mingwei", LockMode::X, true));
+
+        queue.remove(&"joe");
+        queue.remove(&"shadaj");
+        assert_eq!(queue.grant_waiters(), vec![("mingwei", LockMode::X)]);
+    }
+
+    #[test]
+    fn priority_ages_waiters() {
+        let mut queue = LockQueue::new(Fairness::Priority { max_bypass: 2 });
+        queue.acquire(0, LockMode::X);
+        queue.acquire(9, LockMode::X);
+
+        // lower holders go first while 9 ages
+        for holder in 1..3 {
+            assert_eq!(queue.acquire(holder, LockMode::X), AcquireOutcome::Waiting);
+            queue.remove(&(holder - 1));
+            assert_eq!(queue.grant_waiters(), vec![(holder, LockMode::X)]);
+        }
+
+        queue.acquire(3, LockMode::X);
+        queue.remove(&2);
+        assert_eq!(queue.grant_waiters(), vec![(9, LockMode::X)]);
+    }
+
+    #[test]
+    fn grants_waiters_that_starved_later() {
+        let mut queue = LockQueue::new(Fairness::Priority { max_bypass: 1 });
+        queue.acquire(1, LockMode::IX);
+        queue.acquire(3, LockMode::X);
+        queue.acquire(5, LockMode::IS);
+        queue.acquire(4, LockMode::X);
+        queue.cancel(&3);
+        assert_eq!(queue.grant_waiters(), vec![]);
+
+        // 0 passes over both, so 5 now goes ahead of 4, but only 0 is granted here
+        assert_eq!(queue.acquire(0, LockMode::IX), AcquireOutcome::Granted);
+        assert_eq!(queue.waiters().len(), 2);
+        assert_eq!(queue.grant_waiters(), vec![(5, LockMode::IS)]);
+    }
+
+    #[test]
+    fn indexes_entities_by_holder() {
+        let mut table = LockTable::default();
+        table.acquire("foo", "joe", LockMode::X);
+        table.acquire("bar", "joe", LockMode::S);
+        table.acquire("foo", "shadaj", LockMode::S);
+        assert_eq!(table.locks_held(&"joe"), 2);
+        assert!(table.involves(&"shadaj"));
+
+        table.take_changed();
+        table.release(&"bar", &"joe").unwrap();
+        assert_eq!(table.entities_of(&"joe").collect::<Vec<_>>(), vec![&"foo"]);
+        assert_eq!(table.take_changed(), BTreeSet::from(["bar"]));
+
+        assert_eq!(
+            table.release_all(&"joe"),
+            vec![("foo", "shadaj", LockMode::S)]
+        );
+        assert!(!table.involves(&"joe"));
+        assert_eq!(table.locks_held(&"shadaj"), 1);
+    }
+
+    #[test]
+    fn drops_empty_queues() {
+        let mut table = LockTable::default();
+        table.acquire("foo", "joe", LockMode::X);
+        table.acquire("foo", "shadaj", LockMode::S);
+        table.acquire("bar", "shadaj", LockMode::S);
+        let usage = table.usage();
+        assert_eq!((usage.entities, usage.requests), (2, 3));
+
+        table.cancel(&"foo", &"shadaj").unwrap();
+        table.release(&"foo", &"joe").unwrap();
+        assert!(table.queue(&"foo").is_none());
+        table.release_all(&"shadaj");
+        assert_eq!(table.usage(), TableUsage::default());
+    }
+}
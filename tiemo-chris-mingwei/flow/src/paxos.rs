@@ -0,0 +1,756 @@
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::rc::Rc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use hydroflow_plus::*;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use stageleft::*;
+
+#[derive(
+    Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Debug, Default, Hash,
+)]
+pub struct Ballot {
+    // Note: Important that num comes before id, since Ord is defined lexicographically
+    pub num: u32,
+    pub id: u32,
+}
+
+/// A value accepted for a slot. `None` is the no-op a new leader proposes for a slot that no
+/// acceptor it heard from had accepted anything in.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct LogValue<T> {
+    pub ballot: Ballot,
+    pub slot: u32,
+    pub value: Option<T>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct P1a {
+    pub ballot: Ballot,
+    /// Every slot below this one is decided at the campaigner already, so promises leave it out.
+    pub next_decided: u32,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct P1b<T> {
+    pub ballot: Ballot,
+    pub max_ballot: Ballot,
+    /// Values the acceptor accepted for slots it has not seen decided.
+    pub accepted: Vec<LogValue<T>>,
+    /// Values the acceptor has seen decided, which the campaigner learns instead of proposing.
+    pub decided: Vec<(u32, Option<T>)>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct P2a<T> {
+    pub ballot: Ballot,
+    pub slot: u32,
+    pub value: Option<T>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct P2b {
+    pub ballot: Ballot,
+    pub max_ballot: Ballot,
+    pub slot: u32,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum PaxosMessage<T> {
+    P1a(P1a),
+    P1b(P1b<T>),
+    P2a(P2a<T>),
+    P2b(P2b),
+    /// A quorum accepted this value for the slot.
+    Commit(u32, Option<T>),
+    /// The leader of this ballot is still alive, and every member has learned the slots below this
+    /// one.
+    Heartbeat(Ballot, u32),
+    /// The answer to a heartbeat: the sender has learned every slot below this one, and gets sent
+    /// the commits from there on again, in case it missed some.
+    Learned(u32),
+}
+
+/// Everything a Paxos member reacts to.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum PaxosInput<T> {
+    /// A value to put in the log. Only the leader proposes it, so values should be handed to every
+    /// member; the others keep it until they hear from a leader, in case they become the leader
+    /// themselves.
+    Propose(T),
+    /// A message from the member with this id.
+    Receive(u32, PaxosMessage<T>),
+    /// Periodic trigger for the leader to heartbeat and for the others to notice a silent leader,
+    /// carrying the current time since the UNIX epoch.
+    Tick(Duration),
+}
+
+/// One member of a Multi-Paxos group, acting as proposer, acceptor and learner at once. A member
+/// that has not heard from a leader for its election timeout runs phase 1 with a higher ballot;
+/// once a quorum promises, it re-proposes what they had accepted, fills the holes with no-ops, and
+/// from then on proposes new values in phase 2 only. The log survives as long as a quorum of
+/// members does.
+///
+/// The first member starts out leading the lowest ballot, whose phase 1 it can skip since nobody
+/// can have accepted anything before it, so values are sequenced right from the start.
+///
+/// The leader resends the commits a member missed when it answers a heartbeat, and decided values
+/// are dropped once every member has learned them. A member that stays down keeps them from being
+/// dropped, since it may still need them when it comes back.
+#[derive(Clone, Debug)]
+pub struct PaxosNode<T> {
+    id: u32,
+    members: Vec<u32>,
+    election_timeout: Duration,
+
+    /// The highest ballot seen, which the acceptor has promised not to go below.
+    max_ballot: Ballot,
+    /// Values accepted for slots that are not decided here yet.
+    accepted: BTreeMap<u32, LogValue<T>>,
+
+    ballot: Ballot,
+    /// The promises collected so far while running phase 1 for `ballot`.
+    campaign: Option<BTreeMap<u32, P1b<T>>>,
+    leading: bool,
+    next_slot: u32,
+    /// Values proposed by this leader and the acceptors that accepted them, until a quorum has.
+    proposals: BTreeMap<u32, (Option<T>, BTreeSet<u32>)>,
+    /// When to give up on the leader, set by the first tick after it was last heard from.
+    leader_deadline: Option<Duration>,
+    /// Values handed to this member since it last heard from a leader, which it proposes if it
+    /// takes over. Any other leader was handed its own copies.
+    unsequenced: Vec<T>,
+    /// How far each other member has learned the log, as of its latest answer to a heartbeat of
+    /// this leader.
+    learned: BTreeMap<u32, u32>,
+
+    /// Commits for slots beyond a gap, until the gap is filled.
+    committed: BTreeMap<u32, Option<T>>,
+    next_decided: u32,
+    /// Decided values some member may not have learned yet.
+    log: BTreeMap<u32, Option<T>>,
+    /// Every member has learned the slots below this one, so they are no longer in `log`.
+    stable: u32,
+
+    inbox: VecDeque<PaxosMessage<T>>,
+    /// Messages produced by the most recent batch, each with the member it goes to.
+    pub outbox: Vec<(u32, PaxosMessage<T>)>,
+    /// Values that became decided in the most recent batch, with their slots, in slot order.
+    pub decided: Vec<(u32, T)>,
+}
+
+impl<T: Clone> PaxosNode<T> {
+    pub fn new(id: u32, members: Vec<u32>, election_timeout: Duration) -> Self {
+        let first = members.first() == Some(&id);
+        Self {
+            id,
+            members,
+            election_timeout,
+            max_ballot: Ballot::default(),
+            accepted: BTreeMap::new(),
+            ballot: Ballot { num: 0, id },
+            campaign: None,
+            leading: first,
+            next_slot: 0,
+            proposals: BTreeMap::new(),
+            leader_deadline: None,
+            unsequenced: Vec::new(),
+            learned: BTreeMap::new(),
+            committed: BTreeMap::new(),
+            next_decided: 0,
+            log: BTreeMap::new(),
+            stable: 0,
+            inbox: VecDeque::new(),
+            outbox: Vec::new(),
+            decided: Vec::new(),
+        }
+    }
+
+    pub fn is_leader(&self) -> bool {
+        self.leading
+    }
+
+    fn quorum(&self) -> usize {
+        self.members.len() / 2 + 1
+    }
+
+    /// How long to wait for a leader. Members further down the list wait longer, so that they
+    /// rarely campaign against each other.
+    fn patience(&self) -> Duration {
+        let rank = self.members.iter().position(|id| *id == self.id).unwrap();
+        self.election_timeout * (rank as u32 + 1)
+    }
+
+    pub fn apply_batch(&mut self, batch: Vec<PaxosInput<T>>) {
+        self.outbox.clear();
+        self.decided.clear();
+        for input in batch {
+            match input {
+                PaxosInput::Propose(value) => {
+                    if self.leading {
+                        let slot = self.next_slot;
+                        self.next_slot += 1;
+                        self.propose(slot, Some(value));
+                    } else {
+                        self.unsequenced.push(value);
+                    }
+                }
+                PaxosInput::Receive(from, message) => self.receive(from, message),
+                PaxosInput::Tick(now) => self.tick(now),
+            }
+            while let Some(message) = self.inbox.pop_front() {
+                self.receive(self.id, message);
+            }
+        }
+    }
+
+    fn send(&mut self, to: u32, message: PaxosMessage<T>) {
+        if to == self.id {
+            self.inbox.push_back(message);
+        } else {
+            self.outbox.push((to, message));
+        }
+    }
+
+    fn broadcast(&mut self, message: PaxosMessage<T>) {
+        for to in self.members.clone() {
+            self.send(to, message.clone());
+        }
+    }
+
+    fn heartbeat(&mut self) {
+        for to in self.members.clone() {
+            if to != self.id {
+                self.send(to, PaxosMessage::Heartbeat(self.ballot, self.stable));
+            }
+        }
+    }
+
+    fn tick(&mut self, now: Duration) {
+        if self.leading {
+            self.heartbeat();
+            return;
+        }
+
+        let deadline = *self.leader_deadline.get_or_insert(now + self.patience());
+        if deadline <= now {
+            // campaign, and try again after another timeout if that goes nowhere
+            self.leader_deadline = None;
+            self.ballot = Ballot {
+                num: self.max_ballot.num + 1,
+                id: self.id,
+            };
+            self.campaign = Some(BTreeMap::new());
+            self.broadcast(PaxosMessage::P1a(P1a {
+                ballot: self.ballot,
+                next_decided: self.next_decided,
+            }));
+        }
+    }
+
+    /// Notes a ballot seen in a message, giving up leadership or a campaign that it outranks.
+    fn observe(&mut self, ballot: Ballot) {
+        if ballot > self.max_ballot {
+            self.max_ballot = ballot;
+        }
+        if self.max_ballot > self.ballot {
+            self.leading = false;
+            self.campaign = None;
+            self.proposals.clear();
+        }
+    }
+
+    /// Resets the election timeout, and drops the values kept for an election that is not needed.
+    fn heard_from_leader(&mut self) {
+        self.leader_deadline = None;
+        self.unsequenced.clear();
+    }
+
+    fn receive(&mut self, from: u32, message: PaxosMessage<T>) {
+        match message {
+            PaxosMessage::P1a(p1a) => {
+                self.observe(p1a.ballot);
+                if p1a.ballot == self.max_ballot {
+                    self.leader_deadline = None;
+                }
+                let p1b = P1b {
+                    ballot: p1a.ballot,
+                    max_ballot: self.max_ballot,
+                    accepted: self
+                        .accepted
+                        .range(p1a.next_decided..)
+                        .map(|(_, value)| value.clone())
+                        .collect(),
+                    decided: self
+                        .log
+                        .range(p1a.next_decided..)
+                        .map(|(slot, value)| (*slot, value.clone()))
+                        .collect(),
+                };
+                self.send(from, PaxosMessage::P1b(p1b));
+            }
+            PaxosMessage::P1b(p1b) => {
+                self.observe(p1b.max_ballot);
+                if p1b.ballot != self.ballot || p1b.max_ballot != self.ballot {
+                    return;
+                }
+                let Some(promises) = self.campaign.as_mut() else {
+                    return;
+                };
+                promises.insert(from, p1b);
+                if promises.len() >= self.quorum() {
+                    self.lead();
+                }
+            }
+            PaxosMessage::P2a(p2a) => {
+                self.observe(p2a.ballot);
+                if p2a.ballot == self.max_ballot {
+                    self.heard_from_leader();
+                }
+                if p2a.ballot == self.max_ballot && p2a.slot >= self.next_decided {
+                    self.accepted.insert(
+                        p2a.slot,
+                        LogValue {
+                            ballot: p2a.ballot,
+                            slot: p2a.slot,
+                            value: p2a.value,
+                        },
+                    );
+                }
+                let p2b = P2b {
+                    ballot: p2a.ballot,
+                    max_ballot: self.max_ballot,
+                    slot: p2a.slot,
+                };
+                self.send(from, PaxosMessage::P2b(p2b));
+            }
+            PaxosMessage::P2b(p2b) => {
+                self.observe(p2b.max_ballot);
+                if !self.leading || p2b.ballot != self.ballot || p2b.max_ballot != self.ballot {
+                    return;
+                }
+                let quorum = self.quorum();
+                let Some((_, voters)) = self.proposals.get_mut(&p2b.slot) else {
+                    return;
+                };
+                voters.insert(from);
+                if voters.len() >= quorum {
+                    let (value, _) = self.proposals.remove(&p2b.slot).unwrap();
+                    self.broadcast(PaxosMessage::Commit(p2b.slot, value));
+                }
+            }
+            PaxosMessage::Commit(slot, value) => self.commit(slot, value),
+            PaxosMessage::Heartbeat(ballot, stable) => {
+                self.observe(ballot);
+                if ballot == self.max_ballot {
+                    self.heard_from_leader();
+                    self.truncate(stable);
+                    self.send(from, PaxosMessage::Learned(self.next_decided));
+                }
+            }
+            PaxosMessage::Learned(next_decided) => {
+                if !self.leading {
+                    return;
+                }
+                self.learned.insert(from, next_decided);
+                let missed = self
+                    .log
+                    .range(next_decided..)
+                    .map(|(slot, value)| (*slot, value.clone()))
+                    .collect::<Vec<_>>();
+                for (slot, value) in missed {
+                    self.send(from, PaxosMessage::Commit(slot, value));
+                }
+                if self.learned.len() + 1 == self.members.len() {
+                    let stable = self.learned.values().copied().min().unwrap();
+                    self.truncate(stable.min(self.next_decided));
+                }
+            }
+        }
+    }
+
+    fn commit(&mut self, slot: u32, value: Option<T>) {
+        if slot >= self.next_decided {
+            self.committed.insert(slot, value);
+        }
+        while let Some(value) = self.committed.remove(&self.next_decided) {
+            self.log.insert(self.next_decided, value.clone());
+            if let Some(value) = value {
+                self.decided.push((self.next_decided, value));
+            }
+            self.next_decided += 1;
+        }
+        // a campaigner that has not seen these decided gets the decided value instead
+        self.accepted = self.accepted.split_off(&self.next_decided);
+    }
+
+    fn truncate(&mut self, stable: u32) {
+        if stable > self.stable {
+            self.stable = stable;
+            self.log = self.log.split_off(&stable);
+        }
+    }
+
+    /// Takes over after a quorum promised: slots any of them saw decided are learned, and every
+    /// other slot that is not decided here yet gets the value with the highest ballot any of them
+    /// accepted for it, or a no-op. Then the values kept during the election are proposed.
+    fn lead(&mut self) {
+        let promises = self.campaign.take().unwrap();
+        let mut recovered = BTreeMap::<u32, LogValue<T>>::new();
+        for promise in promises.into_values() {
+            for (slot, value) in promise.decided {
+                self.commit(slot, value);
+            }
+            for value in promise.accepted {
+                match recovered.get(&value.slot) {
+                    Some(known) if known.ballot >= value.ballot => {}
+                    _ => {
+                        recovered.insert(value.slot, value);
+                    }
+                }
+            }
+        }
+
+        self.leading = true;
+        self.learned.clear();
+        self.next_slot = recovered
+            .keys()
+            .next_back()
+            .map_or(0, |slot| slot + 1)
+            .max(self.next_decided);
+        for slot in self.next_decided..self.next_slot {
+            let value = recovered.remove(&slot).and_then(|value| value.value);
+            self.propose(slot, value);
+        }
+        for value in std::mem::take(&mut self.unsequenced) {
+            let slot = self.next_slot;
+            self.next_slot += 1;
+            self.propose(slot, Some(value));
+        }
+        self.heartbeat();
+    }
+
+    fn propose(&mut self, slot: u32, value: Option<T>) {
+        self.proposals
+            .insert(slot, (value.clone(), BTreeSet::new()));
+        self.broadcast(PaxosMessage::P2a(P2a {
+            ballot: self.ballot,
+            slot,
+            value,
+        }));
+    }
+}
+
+/// Puts the `values` handed to the members of `replicas` into one log with Multi-Paxos, and
+/// returns the log at every member, in slot order. Members tick every `tick_interval`, and elect a
+/// new leader after hearing nothing from the current one for `election_timeout`.
+///
+/// Only the leader's values make it into the log, so every value should be handed to every member.
+/// Members keep the values handed to them while no leader is heard from, and the next leader
+/// proposes its copies, but values handed to a leader that fails before a quorum accepts them are
+/// lost if the others heard from it in the meantime.
+pub fn sequence<'a, D: Deploy<'a>, T: Serialize + DeserializeOwned + Clone + 'a>(
+    replicas: &D::Cluster,
+    values: Stream<'a, T, stream::Windowed, D::Cluster>,
+    tick_interval: impl Quoted<'a, Duration> + Copy + 'a,
+    election_timeout: impl Quoted<'a, Duration> + Copy + 'a,
+) -> Stream<'a, (u32, T), stream::Windowed, D::Cluster> {
+    let ids = replicas.ids();
+    let self_id = replicas.self_id();
+
+    // messages come back from the other members, which are downstream of this one
+    let (complete_messages, messages) = replicas.cycle();
+
+    let ticks = replicas
+        .source_interval(tick_interval)
+        .tick_batch()
+        .map(q!(|_| PaxosInput::Tick(
+            SystemTime::now().duration_since(UNIX_EPOCH).unwrap()
+        )));
+    let batches = values
+        .map(q!(|value| PaxosInput::Propose(value)))
+        .union(
+            &messages
+                .tick_batch()
+                .map(q!(|(from, message)| PaxosInput::Receive(from, message))),
+        )
+        .union(&ticks)
+        .fold(
+            q!(Vec::new),
+            q!(|batch: &mut Vec<PaxosInput<_>>, input| batch.push(input)),
+        );
+
+    // the member's state is persistent across ticks (all_ticks()), and shared with the ticks that
+    // read it instead of being copied for each of them
+    let node = batches.all_ticks().fold(
+        q!(move || Rc::new(RefCell::new(PaxosNode::new(
+            self_id,
+            ids.clone(),
+            election_timeout
+        )))),
+        q!(|node: &mut Rc<RefCell<PaxosNode<_>>>, batch| node.borrow_mut().apply_batch(batch)),
+    );
+
+    // only ticks that received a batch have fresh messages and decisions, taken out as they go
+    let fresh = batches
+        .map(q!(|_| ()))
+        .cross_product(&node)
+        .map(q!(|(_, node)| node));
+
+    complete_messages.complete(
+        &fresh
+            .flat_map(q!(|node: Rc<RefCell<PaxosNode<_>>>| std::mem::take(
+                &mut node.borrow_mut().outbox
+            )))
+            .demux_bincode_tagged(replicas),
+    );
+
+    fresh.flat_map(q!(|node: Rc<RefCell<PaxosNode<_>>>| std::mem::take(
+        &mut node.borrow_mut().decided
+    )))
+}
+
+/// Sequences the numbers 0 to 9 through Paxos and prints the log at every member.
+pub fn paxos<'a, D: Deploy<'a>>(
+    flow: &'a FlowBuilder<'a, D>,
+    cluster_spec: &impl ClusterSpec<'a, D>,
+) -> D::Cluster {
+    let replicas = flow.cluster(cluster_spec);
+
+    sequence(
+        &replicas,
+        replicas.source_iter(q!(0..10u32)),
+        q!(Duration::from_millis(100)),
+        q!(Duration::from_millis(1000)),
+    )
+    .for_each(q!(|(slot, value): (u32, u32)| println!(
+        "Committed {}: {}",
+        slot, value
+    )));
+
+    replicas
+}
+
+use hydroflow_plus::util::cli::HydroCLI;
+use hydroflow_plus_cli_integration::{CLIRuntime, HydroflowPlusMeta};
+
+#[stageleft::entry]
+pub fn paxos_runtime<'a>(
+    flow: &'a FlowBuilder<'a, CLIRuntime>,
+    cli: RuntimeData<&'a HydroCLI<HydroflowPlusMeta>>,
+) -> impl Quoted<'a, Hydroflow<'a>> {
+    let _ = paxos(flow, &cli);
+    flow.build(q!(cli.meta.subgraph_id))
+}
+
+#[stageleft::runtime]
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::collections::BTreeSet;
+    use std::time::Duration;
+
+    use hydro_deploy::{Deployment, HydroflowCrate};
+    use hydroflow_plus::futures::{self, StreamExt};
+    use hydroflow_plus_cli_integration::{DeployClusterSpec, DeployCrateWrapper};
+
+    use super::{PaxosInput, PaxosNode};
+
+    /// Members that pass messages to each other in rounds, and stop hearing from crashed ones.
+    struct Group {
+        nodes: Vec<PaxosNode<u32>>,
+        crashed: BTreeSet<u32>,
+        logs: Vec<Vec<(u32, u32)>>,
+        now: Duration,
+    }
+
+    impl Group {
+        fn new(size: u32) -> Self {
+            let members = (0..size).collect::<Vec<_>>();
+            Self {
+                nodes: members
+                    .iter()
+                    .map(|id| PaxosNode::new(*id, members.clone(), Duration::from_secs(1)))
+                    .collect(),
+                crashed: BTreeSet::new(),
+                logs: vec![Vec::new(); size as usize],
+                now: Duration::from_secs(100),
+            }
+        }
+
+        /// Hands `inputs` to every live member, then delivers messages until none are left.
+        fn run(&mut self, inputs: Vec<PaxosInput<u32>>) {
+            let mut batches = vec![inputs; self.nodes.len()];
+            while batches.iter().any(|batch| !batch.is_empty()) {
+                let mut next = vec![Vec::new(); self.nodes.len()];
+                for (id, batch) in batches.into_iter().enumerate() {
+                    if self.crashed.contains(&(id as u32)) {
+                        continue;
+                    }
+                    let node = &mut self.nodes[id];
+                    node.apply_batch(batch);
+                    self.logs[id].extend(node.decided.iter().copied());
+                    for (to, message) in node.outbox.drain(..) {
+                        next[to as usize].push(PaxosInput::Receive(id as u32, message));
+                    }
+                }
+                batches = next;
+            }
+        }
+
+        fn tick(&mut self, by: Duration) {
+            self.now += by;
+            self.run(vec![PaxosInput::Tick(self.now)]);
+        }
+
+        fn leaders(&self) -> Vec<u32> {
+            (0..self.nodes.len() as u32)
+                .filter(|id| !self.crashed.contains(id) && self.nodes[*id as usize].is_leader())
+                .collect()
+        }
+    }
+
+    #[test]
+    fn survives_leader_crash() {
+        let mut group = Group::new(3);
+        assert_eq!(group.leaders(), vec![0]);
+
+        group.run(vec![PaxosInput::Propose(1), PaxosInput::Propose(2)]);
+        assert!(group.logs.iter().all(|log| *log == [(0, 1), (1, 2)]));
+
+        // the leader sends its proposal to member 1 only, and crashes
+        group.nodes[0].apply_batch(vec![PaxosInput::Propose(3)]);
+        let (to, message) = group.nodes[0]
+            .outbox
+            .iter()
+            .find(|(to, _)| *to == 1)
+            .cloned()
+            .unwrap();
+        group.crashed.insert(0);
+        group.nodes[to as usize].apply_batch(vec![PaxosInput::Receive(0, message)]);
+
+        // member 1 takes over once its longer timeout runs out, and keeps the accepted value
+        for _ in 0..3 {
+            group.tick(Duration::from_secs(1));
+        }
+        assert_eq!(group.leaders(), vec![1]);
+        group.run(vec![PaxosInput::Propose(4)]);
+
+        let expected = [(0, 1), (1, 2), (2, 3), (3, 4)];
+        assert_eq!(group.logs[1], expected);
+        assert_eq!(group.logs[2], expected);
+    }
+
+    #[test]
+    fn fills_holes_with_no_ops() {
+        let mut group = Group::new(3);
+
+        // two proposals, of which only the second reaches another member before the crash
+        group.nodes[0].apply_batch(vec![PaxosInput::Propose(5), PaxosInput::Propose(6)]);
+        let messages = group.nodes[0]
+            .outbox
+            .iter()
+            .filter(|(to, _)| *to == 2)
+            .cloned()
+            .collect::<Vec<_>>();
+        group.crashed.insert(0);
+        let (_, second) = messages.last().cloned().unwrap();
+        group.nodes[2].apply_batch(vec![PaxosInput::Receive(0, second)]);
+
+        for _ in 0..3 {
+            group.tick(Duration::from_secs(1));
+        }
+        assert_eq!(group.leaders(), vec![1]);
+        assert_eq!(group.logs[1], [(1, 6)]);
+        assert_eq!(group.logs[2], [(1, 6)]);
+    }
+
+    #[test]
+    fn proposes_values_handed_over_during_election() {
+        let mut group = Group::new(3);
+        group.crashed.insert(0);
+
+        // nobody leads, so the others keep the value and the one that takes over proposes it
+        group.run(vec![PaxosInput::Propose(7)]);
+        assert!(group.logs[1].is_empty());
+        for _ in 0..3 {
+            group.tick(Duration::from_secs(1));
+        }
+        assert_eq!(group.leaders(), vec![1]);
+        assert_eq!(group.logs[1], [(0, 7)]);
+        assert_eq!(group.logs[2], [(0, 7)]);
+    }
+
+    #[test]
+    fn catches_up_missed_commits() {
+        let mut group = Group::new(3);
+
+        // member 2 is cut off while two values are decided without it
+        group.crashed.insert(2);
+        group.run(vec![PaxosInput::Propose(1), PaxosInput::Propose(2)]);
+        group.crashed.remove(&2);
+        assert!(group.logs[2].is_empty());
+
+        // it learns them from the leader when it answers a heartbeat
+        group.tick(Duration::from_millis(100));
+        assert_eq!(group.logs[2], [(0, 1), (1, 2)]);
+
+        // and once everyone has, and the leader has told them so, nobody keeps them any more
+        for _ in 0..2 {
+            group.tick(Duration::from_millis(100));
+        }
+        for node in &group.nodes {
+            assert!(node.log.is_empty() && node.accepted.is_empty());
+        }
+    }
+
+    #[tokio::test]
+    async fn paxos() {
+        let mut deployment = Deployment::new();
+        let localhost = deployment.Localhost();
+        let deployment = RefCell::new(deployment);
+
+        let flow = hydroflow_plus::FlowBuilder::new();
+        let replicas = super::paxos(
+            &flow,
+            &DeployClusterSpec::new(|| {
+                (0..3)
+                    .map(|idx| {
+                        deployment.borrow_mut().add_service(
+                            HydroflowCrate::new(".", localhost.clone())
+                                .bin("paxos")
+                                .profile("dev")
+                                .display_name(format!("replica/{}", idx)),
+                        )
+                    })
+                    .collect()
+            }),
+        );
+
+        let mut deployment = deployment.into_inner();
+        deployment.deploy().await.unwrap();
+
+        let mut stdout = futures::stream::select_all(
+            futures::future::join_all(replicas.members().iter().map(|member| member.stdout()))
+                .await,
+        );
+
+        deployment.start().await.unwrap();
+
+        // every replica learns the whole log
+        let mut lines = Vec::new();
+        tokio::time::timeout(Duration::from_secs(30), async {
+            while lines.len() < 30 {
+                lines.push(stdout.next().await.unwrap());
+            }
+        })
+        .await
+        .unwrap();
+        for value in 0..10 {
+            let line = format!("Committed {}: {}", value, value);
+            assert_eq!(lines.iter().filter(|printed| **printed == line).count(), 3);
+        }
+    }
+}
@@ -0,0 +1,421 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use hydroflow_plus::*;
+use stageleft::*;
+
+use crate::deadlock::VictimPolicy;
+use crate::first_ten_distributed::{
+    begin_transactions, ClientRequest, Key, LockConfig, LockInput, LockManager, LockResponse,
+    MachineId,
+};
+use crate::heartbeats::failure_detector;
+use crate::lock_client::ClientPorts;
+use crate::paxos::sequence;
+
+/// Where a response was produced in the replicated log: the slot of the batch it answers, and its
+/// place among that batch's responses. Every replica produces the same response at each position.
+pub type LogPosition = (u32, usize);
+
+/// A lock manager applying the batches decided in the Paxos log, in slot order, like every other
+/// replica does.
+#[derive(Clone, Debug, Default)]
+pub struct ReplicatedLockManager {
+    pub manager: LockManager,
+    /// Responses produced by the most recent batches, each with the client it goes to.
+    pub outbox: Vec<(u32, (LogPosition, LockResponse))>,
+}
+
+impl ReplicatedLockManager {
+    pub fn new(config: LockConfig) -> Self {
+        Self {
+            manager: LockManager::new(config),
+            outbox: Vec::new(),
+        }
+    }
+
+    pub fn apply_batch(&mut self, decided: Vec<(u32, Vec<LockInput>)>) {
+        self.outbox.clear();
+        for (slot, batch) in decided {
+            self.manager.apply_batch(batch);
+            let responses = self.manager.outbox.drain(..).enumerate();
+            self.outbox.extend(
+                responses.map(|(index, (client, response))| (client, ((slot, index), response))),
+            );
+        }
+    }
+}
+
+/// The responses a client received from every replica, of which each is passed on once.
+#[derive(Clone, Debug, Default)]
+pub struct ReplicaResponses {
+    delivered: Option<LogPosition>,
+    /// Responses from the most recent batch that had not been passed on yet, in log order.
+    pub fresh: Vec<LockResponse>,
+}
+
+impl ReplicaResponses {
+    /// Keeps the responses further along the log than any passed on so far. Each replica sends its
+    /// responses in log order, so by the time a copy arrives from another replica, the one that
+    /// arrived first is at least as far along.
+    pub fn apply_batch(&mut self, mut batch: Vec<(LogPosition, LockResponse)>) {
+        self.fresh.clear();
+        batch.sort_by_key(|(position, _)| *position);
+        for (position, response) in batch {
+            if self.delivered < Some(position) {
+                self.delivered = Some(position);
+                self.fresh.push(response);
+            }
+        }
+    }
+}
+
+/// Runs one unsharded lock service on every member of `replicas`, kept in step by sequencing all
+/// its inputs through Paxos, and returns the responses at the client that issued each request. The
+/// service keeps granting locks as long as a majority of replicas is up, and since every replica
+/// applies the same inputs in the same order, the locks are granted as one lock manager would.
+///
+/// Clients send every request to every replica, and each replica gathers its inputs for a tick into
+/// one batch, along with deadlock detection every `detection_interval`, expiries every
+/// `expiry_interval`, and the transactions of machines that show up in `failed_machines`. Only the
+/// Paxos leader's batches make it into the log, and during an election the next leader's. Requests
+/// a leader took just before failing, without a majority accepting them, are lost. Every replica
+/// answers, and clients keep one copy of each response.
+///
+/// A replica that has not heard from the leader for `election_timeout` tries to take over.
+#[allow(clippy::too_many_arguments)]
+pub fn replicated_lock_service<'a, D: Deploy<'a>>(
+    clients: &D::Cluster,
+    replicas: &D::Cluster,
+    begin_reqs: Stream<'a, (), stream::Windowed, D::Cluster>,
+    requests: Stream<'a, (Key, ClientRequest), stream::Windowed, D::Cluster>,
+    failed_machines: Stream<'a, MachineId, stream::Windowed, D::Cluster>,
+    detection_interval: impl Quoted<'a, Duration> + Copy + 'a,
+    victim_policy: impl Quoted<'a, VictimPolicy> + Copy + 'a,
+    expiry_interval: impl Quoted<'a, Duration> + Copy + 'a,
+    election_timeout: impl Quoted<'a, Duration> + Copy + 'a,
+    config: impl Quoted<'a, LockConfig> + Copy + 'a,
+) -> Stream<'a, LockResponse, stream::Windowed, D::Cluster> {
+    let began = begin_transactions(clients, begin_reqs);
+
+    let detections = replicas
+        .source_interval(detection_interval)
+        .tick_batch()
+        .map(q!(move |_| LockInput::DetectDeadlocks(victim_policy)));
+    let expiries = replicas
+        .source_interval(expiry_interval)
+        .tick_batch()
+        .map(q!(|_| LockInput::Expire(
+            SystemTime::now().duration_since(UNIX_EPOCH).unwrap()
+        )));
+
+    // at every replica: gather each tick's inputs into one batch, and propose it for the log
+    let batches = requests
+        .broadcast_bincode_tagged(replicas)
+        .tick_batch()
+        .map(q!(|(client, (key, request))| LockInput::Request(
+            client, key, request
+        )))
+        .union(&detections)
+        .union(&expiries)
+        .union(&failed_machines.map(q!(|machine| LockInput::MachineFailed(machine))))
+        .fold(
+            q!(Vec::new),
+            q!(|batch: &mut Vec<LockInput>, input| batch.push(input)),
+        );
+    let decided = sequence(
+        replicas,
+        batches,
+        q!(election_timeout / 10),
+        election_timeout,
+    )
+    .fold(
+        q!(Vec::new),
+        q!(|decided: &mut Vec<(u32, Vec<LockInput>)>, entry| decided.push(entry)),
+    );
+
+    // the lock table is persistent state across ticks (all_ticks()), behind a handle so that
+    // reading its responses does not copy it
+    let manager = decided.all_ticks().fold(
+        q!(move || Rc::new(RefCell::new(ReplicatedLockManager::new(config)))),
+        q!(|manager: &mut Rc<RefCell<ReplicatedLockManager>>,
+            decided: Vec<(u32, Vec<LockInput>)>| {
+            manager.borrow_mut().apply_batch(decided)
+        }),
+    );
+
+    // at the clients: keep the first copy of every response
+    let responses = decided
+        .map(q!(|_| ()))
+        .cross_product(&manager)
+        .map(q!(|(_, manager)| manager))
+        .flat_map(q!(|manager: Rc<RefCell<ReplicatedLockManager>>| {
+            std::mem::take(&mut manager.borrow_mut().outbox)
+        }))
+        .demux_bincode(clients)
+        .tick_batch()
+        .fold(
+            q!(Vec::new),
+            q!(|batch: &mut Vec<(LogPosition, LockResponse)>, response| batch.push(response)),
+        );
+    let delivered = responses.all_ticks().fold(
+        q!(|| Rc::new(RefCell::new(ReplicaResponses::default()))),
+        q!(|delivered: &mut Rc<RefCell<ReplicaResponses>>,
+            batch: Vec<(LogPosition, LockResponse)>| {
+            delivered.borrow_mut().apply_batch(batch)
+        }),
+    );
+
+    responses
+        .map(q!(|_| ()))
+        .cross_product(&delivered)
+        .flat_map(q!(|(_, delivered): ((), Rc<RefCell<ReplicaResponses>>)| {
+            std::mem::take(&mut delivered.borrow_mut().fresh)
+        }))
+        .union(&began)
+}
+
+/// Runs the replicated lock service for clients that use [`LockClient`]s, with `client_ports` being
+/// the dataflow's side of the client on each client member. Returns the clients.
+///
+/// [`LockClient`]: crate::lock_client::LockClient
+pub fn first_ten_replicated<'a, D: Deploy<'a>>(
+    flow: &'a FlowBuilder<'a, D>,
+    cluster_spec: &impl ClusterSpec<'a, D>,
+    client_ports: RuntimeData<ClientPorts>,
+) -> D::Cluster {
+    let clients = flow.cluster(cluster_spec);
+    let replicas = flow.cluster(cluster_spec);
+
+    let begin_reqs = clients.source_stream(q!(client_ports.begins)).tick_batch();
+    let requests = clients
+        .source_stream(q!(client_ports.requests))
+        .tick_batch();
+    let failed_machines = failure_detector(&clients, q!(0.0))
+        .map(q!(|id| MachineId(id as usize)))
+        .broadcast_bincode(&replicas)
+        .tick_batch();
+
+    replicated_lock_service(
+        &clients,
+        &replicas,
+        begin_reqs,
+        requests,
+        failed_machines,
+        q!(Duration::from_millis(1000)),
+        q!(VictimPolicy::Youngest),
+        q!(Duration::from_millis(100)),
+        q!(Duration::from_millis(1000)),
+        q!(LockConfig::default()),
+    )
+    .for_each(q!(move |response| client_ports.responses.deliver(response)));
+
+    clients
+}
+
+use hydroflow_plus::util::cli::HydroCLI;
+use hydroflow_plus_cli_integration::{CLIRuntime, HydroflowPlusMeta};
+
+#[stageleft::entry]
+pub fn first_ten_replicated_runtime<'a>(
+    flow: &'a FlowBuilder<'a, CLIRuntime>,
+    cli: RuntimeData<&'a HydroCLI<HydroflowPlusMeta>>,
+    client_ports: RuntimeData<ClientPorts>,
+) -> impl Quoted<'a, Hydroflow<'a>> {
+    first_ten_replicated(flow, &cli, client_ports);
+    flow.build(q!(cli.meta.subgraph_id))
+}
+
+#[stageleft::runtime]
+#[cfg(test)]
+mod tests {
+    use std::cell::RefCell;
+    use std::time::Duration;
+
+    use hydro_deploy::{Deployment, HydroflowCrate};
+    use hydroflow_plus::futures::{self, StreamExt};
+    use hydroflow_plus_cli_integration::{DeployClusterSpec, DeployCrateWrapper};
+    use stageleft::RuntimeData;
+
+    use super::{ReplicaResponses, ReplicatedLockManager};
+    use crate::first_ten_distributed::{
+        ClientRequest, EntityId, Key, LockConfig, LockInput, LockResponse, MachineId, TransactionId,
+    };
+    use crate::hierarchy::EntityPath;
+    use crate::lock_mode::LockMode;
+    use crate::paxos::{PaxosInput, PaxosNode};
+
+    fn key(transaction_id: usize) -> Key {
+        Key {
+            transaction_id: TransactionId(transaction_id),
+            machine_id: MachineId(0),
+        }
+    }
+
+    fn request(transaction_id: usize, request: ClientRequest) -> Vec<LockInput> {
+        vec![LockInput::Request(0, key(transaction_id), request)]
+    }
+
+    fn acquire(transaction_id: usize) -> Vec<LockInput> {
+        let acquire = ClientRequest::Acquire {
+            entity: EntityPath(vec![EntityId(0)]),
+            mode: LockMode::X,
+        };
+        request(transaction_id, acquire)
+    }
+
+    /// Replicas exchanging Paxos messages until none are left, which all send their responses to
+    /// one client.
+    struct Replicas {
+        nodes: Vec<PaxosNode<Vec<LockInput>>>,
+        managers: Vec<ReplicatedLockManager>,
+        crashed: Vec<bool>,
+        client: ReplicaResponses,
+        now: Duration,
+    }
+
+    impl Replicas {
+        fn new(size: u32) -> Self {
+            let members = (0..size).collect::<Vec<_>>();
+            Self {
+                nodes: members
+                    .iter()
+                    .map(|id| PaxosNode::new(*id, members.clone(), Duration::from_secs(1)))
+                    .collect(),
+                managers: vec![ReplicatedLockManager::new(LockConfig::default()); size as usize],
+                crashed: vec![false; size as usize],
+                client: ReplicaResponses::default(),
+                now: Duration::from_secs(100),
+            }
+        }
+
+        /// Hands `inputs` to every live replica, and returns the responses the client keeps.
+        fn run(&mut self, inputs: Vec<PaxosInput<Vec<LockInput>>>) -> Vec<LockResponse> {
+            let mut batches = vec![inputs; self.nodes.len()];
+            let mut responses = Vec::new();
+            while batches.iter().any(|batch| !batch.is_empty()) {
+                let mut next = vec![Vec::new(); self.nodes.len()];
+                for (id, batch) in batches.into_iter().enumerate() {
+                    if self.crashed[id] {
+                        continue;
+                    }
+                    let node = &mut self.nodes[id];
+                    node.apply_batch(batch);
+                    for (to, message) in node.outbox.drain(..) {
+                        next[to as usize].push(PaxosInput::Receive(id as u32, message));
+                    }
+                    self.managers[id].apply_batch(node.decided.drain(..).collect());
+                    responses.extend(self.managers[id].outbox.drain(..).map(|(_, r)| r));
+                }
+                batches = next;
+            }
+            self.client.apply_batch(responses);
+            self.client.fresh.clone()
+        }
+
+        fn propose(&mut self, batch: Vec<LockInput>) -> Vec<LockResponse> {
+            self.run(vec![PaxosInput::Propose(batch)])
+        }
+
+        fn tick(&mut self) -> Vec<LockResponse> {
+            self.now += Duration::from_secs(1);
+            self.run(vec![PaxosInput::Tick(self.now)])
+        }
+    }
+
+    #[test]
+    fn grants_after_leader_fails() {
+        let mut replicas = Replicas::new(3);
+        let entity = EntityPath(vec![EntityId(0)]);
+
+        // every replica answers, but the client sees each response once
+        assert_eq!(
+            replicas.propose(acquire(1)),
+            vec![LockResponse::Granted {
+                key: key(1),
+                entity: entity.clone(),
+                mode: LockMode::X,
+            }]
+        );
+        assert_eq!(
+            replicas.propose(acquire(2)),
+            vec![LockResponse::Queued {
+                key: key(2),
+                entity: entity.clone(),
+                mode: LockMode::X,
+            }]
+        );
+
+        // the leader fails, and requests wait at the others until one of them takes over
+        replicas.crashed[0] = true;
+        assert_eq!(replicas.propose(request(1, ClientRequest::Commit)), vec![]);
+        let mut responses = Vec::new();
+        for _ in 0..3 {
+            responses.extend(replicas.tick());
+        }
+        assert!(replicas.nodes[1].is_leader());
+
+        // the new leader still has the lock held and the waiter queued
+        assert_eq!(
+            responses,
+            vec![
+                LockResponse::Committed {
+                    key: key(1),
+                    shard: 0,
+                    shards: 1,
+                },
+                LockResponse::Granted {
+                    key: key(2),
+                    entity: entity.clone(),
+                    mode: LockMode::X,
+                },
+            ]
+        );
+        for manager in &replicas.managers[1..] {
+            let queue = manager.manager.table.table().queue(&entity).unwrap();
+            assert_eq!(queue.holders(), &[(key(2), LockMode::X)]);
+        }
+    }
+
+    #[tokio::test]
+    async fn breaks_deadlock_with_replicas() {
+        let mut deployment = Deployment::new();
+        let localhost = deployment.Localhost();
+        let deployment = RefCell::new(deployment);
+        let service = || {
+            deployment.borrow_mut().add_service(
+                HydroflowCrate::new(".", localhost.clone())
+                    .bin("first_ten_replicated")
+                    .profile("dev"),
+            )
+        };
+
+        let flow = hydroflow_plus::FlowBuilder::new();
+        let clients = super::first_ten_replicated(
+            &flow,
+            &DeployClusterSpec::new(|| vec![service(), service(), service()]),
+            RuntimeData::new("FAKE"),
+        );
+
+        let mut deployment = deployment.into_inner();
+        deployment.deploy().await.unwrap();
+
+        let mut stdout = futures::stream::select_all(
+            futures::future::join_all(clients.members().iter().map(|member| member.stdout())).await,
+        );
+
+        deployment.start().await.unwrap();
+
+        let (mut granted, mut aborted) = (false, false);
+        tokio::time::timeout(Duration::from_secs(30), async {
+            while !(granted && aborted) {
+                let line = stdout.next().await.unwrap();
+                granted |= line == "Ok(())";
+                aborted |= line.starts_with("Err(Aborted") && line.contains("cause: Deadlock");
+            }
+        })
+        .await
+        .unwrap();
+    }
+}
@@ -0,0 +1,172 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::marker::PhantomData;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+/// An append-only file of records, each written and synced to disk before [`WriteAheadLog::append`]
+/// returns. Records are bincode, prefixed with their length as a little-endian `u64`. Clones write
+/// to the same file.
+#[derive(Debug)]
+pub struct WriteAheadLog<T> {
+    path: PathBuf,
+    file: Arc<Mutex<File>>,
+    _record: PhantomData<fn(T)>,
+}
+
+impl<T> Clone for WriteAheadLog<T> {
+    fn clone(&self) -> Self {
+        Self {
+            path: self.path.clone(),
+            file: self.file.clone(),
+            _record: PhantomData,
+        }
+    }
+}
+
+fn frame<T: Serialize>(record: &T) -> io::Result<Vec<u8>> {
+    let record = bincode::serialize(record).map_err(io::Error::other)?;
+    let mut buf = Vec::with_capacity(8 + record.len());
+    buf.extend((record.len() as u64).to_le_bytes());
+    buf.extend(record);
+    Ok(buf)
+}
+
+impl<T: Serialize + DeserializeOwned> WriteAheadLog<T> {
+    /// Opens the log at `path`, creating it if needed, and returns the records already in it. A
+    /// record cut short by a crash in the middle of an append is dropped from the file.
+    pub fn open(path: impl AsRef<Path>) -> io::Result<(Self, Vec<T>)> {
+        let path = path.as_ref().to_path_buf();
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(&path)?;
+        let mut contents = Vec::new();
+        file.read_to_end(&mut contents)?;
+
+        let mut records = Vec::new();
+        let mut offset = 0;
+        while let Some(len) = contents.get(offset..offset + 8) {
+            let len = u64::from_le_bytes(len.try_into().unwrap()) as usize;
+            let Some(record) = contents.get(offset + 8..offset + 8 + len) else {
+                break;
+            };
+            records.push(bincode::deserialize(record).map_err(io::Error::other)?);
+            offset += 8 + len;
+        }
+        file.set_len(offset as u64)?;
+        file.seek(SeekFrom::End(0))?;
+
+        let log = Self {
+            path,
+            file: Arc::new(Mutex::new(file)),
+            _record: PhantomData,
+        };
+        Ok((log, records))
+    }
+
+    /// Adds `record` to the end of the log. If that fails, the log is cut back to where it was, so
+    /// that nothing is left of the record to get in the way of the next one.
+    pub fn append(&self, record: &T) -> io::Result<()> {
+        let buf = frame(record)?;
+        let mut file = self.file.lock().unwrap();
+        let end = file.stream_position()?;
+        let written = file.write_all(&buf).and_then(|()| file.sync_data());
+        if written.is_err() {
+            let _ = file.set_len(end);
+            let _ = file.seek(SeekFrom::Start(end));
+        }
+        written
+    }
+
+    /// Replaces every record in the log with `record`. The new log is written next to the old one
+    /// and then renamed over it, so a crash leaves one or the other, never a mix.
+    pub fn rewrite(&self, record: &T) -> io::Result<()> {
+        let buf = frame(record)?;
+        let mut file = self.file.lock().unwrap();
+        let mut temp = self.path.clone().into_os_string();
+        temp.push(".new");
+        let mut new = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(&temp)?;
+        new.write_all(&buf)?;
+        new.sync_data()?;
+        std::fs::rename(&temp, &self.path)?;
+        // the rename is only durable once the directory is synced too
+        let dir = match self.path.parent() {
+            Some(dir) if !dir.as_os_str().is_empty() => dir,
+            _ => Path::new("."),
+        };
+        File::open(dir)?.sync_all()?;
+        *file = new;
+        Ok(())
+    }
+
+    /// How many bytes the log takes up.
+    pub fn size(&self) -> io::Result<u64> {
+        self.file.lock().unwrap().stream_position()
+    }
+}
+
+#[stageleft::runtime]
+#[cfg(test)]
+mod tests {
+    use std::fs::OpenOptions;
+    use std::io::Write;
+
+    use super::WriteAheadLog;
+
+    #[test]
+    fn drops_torn_record() {
+        let path = std::env::temp_dir().join(format!("wal-torn-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let (log, records) = WriteAheadLog::<Vec<u32>>::open(&path).unwrap();
+        assert!(records.is_empty());
+        log.append(&vec![1, 2]).unwrap();
+        log.append(&vec![3]).unwrap();
+        drop(log);
+
+        // a crash halfway through writing a third record
+        let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&100u64.to_le_bytes()).unwrap();
+        file.write_all(&[0; 10]).unwrap();
+        drop(file);
+
+        let (log, records) = WriteAheadLog::<Vec<u32>>::open(&path).unwrap();
+        assert_eq!(records, vec![vec![1, 2], vec![3]]);
+        log.append(&vec![4]).unwrap();
+        drop(log);
+        let (_, records) = WriteAheadLog::<Vec<u32>>::open(&path).unwrap();
+        assert_eq!(records, vec![vec![1, 2], vec![3], vec![4]]);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn rewrite_replaces_every_record() {
+        let path = std::env::temp_dir().join(format!("wal-rewrite-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let (log, _) = WriteAheadLog::<Vec<u32>>::open(&path).unwrap();
+        log.append(&vec![1, 2]).unwrap();
+        log.append(&vec![3]).unwrap();
+        log.rewrite(&vec![1, 2, 3]).unwrap();
+        log.append(&vec![4]).unwrap();
+        assert_eq!(log.size().unwrap(), std::fs::metadata(&path).unwrap().len());
+        drop(log);
+
+        let (_, records) = WriteAheadLog::<Vec<u32>>::open(&path).unwrap();
+        assert_eq!(records, vec![vec![1, 2, 3], vec![4]]);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
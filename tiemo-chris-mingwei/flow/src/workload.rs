@@ -0,0 +1,561 @@
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use hydroflow_plus::*;
+use lattices::Merge;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use stageleft::*;
+
+use crate::deadlock::{Prevention, VictimPolicy};
+use crate::first_ten_distributed::{serve_lock_clients, EntityId, LockConfig};
+use crate::hierarchy::EntityPath;
+use crate::lock_client::{ClientPorts, LockClient};
+use crate::lock_mode::LockMode;
+use crate::lock_table::Fairness;
+
+/// A closed-loop workload: every client runs one transaction after another, each starting as soon
+/// as the previous one ended, for `duration`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct WorkloadConfig {
+    /// Transactions running at once on each client member.
+    pub clients: usize,
+    /// The entities at the bottom of the hierarchy, which are the ones transactions lock.
+    pub entities: usize,
+    /// The exponent of the Zipf distribution entities are drawn from. 0 is uniform, and the larger
+    /// it is, the more the first entities are favoured.
+    pub zipf_skew: f64,
+    /// The share of locks taken in S rather than X.
+    pub read_fraction: f64,
+    /// Locks per transaction.
+    pub transaction_length: usize,
+    /// Levels of the hierarchy, so that every entity path is this long. Transactions take
+    /// intention locks on the levels above the entities they lock.
+    pub hierarchy_depth: usize,
+    pub duration: Duration,
+    /// How the lock service under test runs.
+    pub lock: LockConfig,
+    pub victim_policy: VictimPolicy,
+}
+
+impl Default for WorkloadConfig {
+    fn default() -> Self {
+        Self {
+            clients: 8,
+            entities: 1000,
+            zipf_skew: 0.0,
+            read_fraction: 0.5,
+            transaction_length: 4,
+            hierarchy_depth: 1,
+            duration: Duration::from_secs(10),
+            lock: LockConfig::default(),
+            victim_policy: VictimPolicy::Youngest,
+        }
+    }
+}
+
+/// Reads the environment variable `name`, or returns `default` if it is not set.
+fn var<T: FromStr>(name: &str, default: T) -> T {
+    match std::env::var(name) {
+        Ok(value) => parse(name, &value),
+        Err(_) => default,
+    }
+}
+
+/// Parses `value`, read from the environment variable `name`.
+fn parse<T: FromStr>(name: &str, value: &str) -> T {
+    value
+        .parse()
+        .unwrap_or_else(|_| panic!("{} has an invalid value {:?}", name, value))
+}
+
+impl WorkloadConfig {
+    /// The defaults, overridden by whichever of these environment variables are set:
+    ///
+    /// - `WORKLOAD_CLIENTS`, `WORKLOAD_ENTITIES`, `WORKLOAD_ZIPF_SKEW`, `WORKLOAD_READ_FRACTION`,
+    ///   `WORKLOAD_TRANSACTION_LENGTH`, `WORKLOAD_HIERARCHY_DEPTH`, and `WORKLOAD_SECONDS` for
+    ///   the duration
+    /// - `WORKLOAD_FAIRNESS`, one of `fifo`, `jump:<max bypass>` or `priority:<max bypass>`
+    /// - `WORKLOAD_PREVENTION`, one of `none`, `wait-die` or `wound-wait`
+    /// - `WORKLOAD_VICTIM`, one of `youngest` or `fewest-locks`
+    /// - `WORKLOAD_TIMEOUT_MS`, how long an acquire waits before it times out
+    pub fn from_env() -> Self {
+        let default = Self::default();
+        let fairness = var("WORKLOAD_FAIRNESS", "fifo".to_owned());
+        let fairness = match fairness.split_once(':') {
+            None if fairness == "fifo" => Fairness::Fifo,
+            Some(("jump", max_bypass)) => Fairness::CompatibleJump {
+                max_bypass: parse("WORKLOAD_FAIRNESS", max_bypass),
+            },
+            Some(("priority", max_bypass)) => Fairness::Priority {
+                max_bypass: parse("WORKLOAD_FAIRNESS", max_bypass),
+            },
+            _ => panic!("unknown fairness policy {:?}", fairness),
+        };
+        let prevention = match var("WORKLOAD_PREVENTION", "none".to_owned()).as_str() {
+            "none" => None,
+            "wait-die" => Some(Prevention::WaitDie),
+            "wound-wait" => Some(Prevention::WoundWait),
+            other => panic!("unknown prevention scheme {:?}", other),
+        };
+        let victim_policy = match var("WORKLOAD_VICTIM", "youngest".to_owned()).as_str() {
+            "youngest" => VictimPolicy::Youngest,
+            "fewest-locks" => VictimPolicy::FewestLocks,
+            other => panic!("unknown victim policy {:?}", other),
+        };
+        Self {
+            clients: var("WORKLOAD_CLIENTS", default.clients),
+            entities: var("WORKLOAD_ENTITIES", default.entities),
+            zipf_skew: var("WORKLOAD_ZIPF_SKEW", default.zipf_skew),
+            read_fraction: var("WORKLOAD_READ_FRACTION", default.read_fraction),
+            transaction_length: var("WORKLOAD_TRANSACTION_LENGTH", default.transaction_length),
+            hierarchy_depth: var("WORKLOAD_HIERARCHY_DEPTH", default.hierarchy_depth),
+            duration: Duration::from_secs(var("WORKLOAD_SECONDS", default.duration.as_secs())),
+            lock: LockConfig {
+                prevention,
+                default_timeout: std::env::var("WORKLOAD_TIMEOUT_MS")
+                    .ok()
+                    .map(|ms| Duration::from_millis(parse("WORKLOAD_TIMEOUT_MS", &ms))),
+                fairness,
+                ..default.lock
+            },
+            victim_policy,
+        }
+    }
+}
+
+/// Draws the transactions of a [`WorkloadConfig`].
+#[derive(Clone, Debug)]
+pub struct TransactionGenerator {
+    /// The probability of drawing each entity or one before it.
+    cumulative: Vec<f64>,
+    /// Children of every entity above the bottom of the hierarchy.
+    fanout: usize,
+    depth: usize,
+    read_fraction: f64,
+    length: usize,
+}
+
+impl TransactionGenerator {
+    pub fn new(config: &WorkloadConfig) -> Self {
+        let weights = (1..=config.entities)
+            .map(|rank| 1.0 / (rank as f64).powf(config.zipf_skew))
+            .collect::<Vec<_>>();
+        let total = weights.iter().sum::<f64>();
+        let cumulative = weights
+            .iter()
+            .scan(0.0, |sum, weight| {
+                *sum += weight / total;
+                Some(*sum)
+            })
+            .collect();
+        let depth = config.hierarchy_depth.max(1);
+        // the smallest fanout that gives every entity a path of its own
+        let mut fanout = 1_usize;
+        while fanout.pow(depth as u32) < config.entities {
+            fanout += 1;
+        }
+        Self {
+            cumulative,
+            fanout,
+            depth,
+            read_fraction: config.read_fraction,
+            length: config.transaction_length,
+        }
+    }
+
+    /// The path of the entity with the given rank, as its digits in base `fanout`.
+    fn path(&self, rank: usize) -> EntityPath<EntityId> {
+        let mut path = (0..self.depth)
+            .scan(rank, |rest, _| {
+                let digit = *rest % self.fanout;
+                *rest /= self.fanout;
+                Some(EntityId(digit))
+            })
+            .collect::<Vec<_>>();
+        path.reverse();
+        EntityPath(path)
+    }
+
+    /// The locks of the next transaction, in the order it acquires them. An entity drawn twice is
+    /// locked once, in the join of its modes.
+    pub fn next(&self, rng: &mut impl Rng) -> Vec<(EntityPath<EntityId>, LockMode)> {
+        let mut locks = Vec::<(EntityPath<EntityId>, LockMode)>::new();
+        for _ in 0..self.length {
+            let draw = rng.gen::<f64>();
+            let rank = self
+                .cumulative
+                .partition_point(|cumulative| *cumulative < draw)
+                .min(self.cumulative.len() - 1);
+            let entity = self.path(rank);
+            let mode = if rng.gen_bool(self.read_fraction) {
+                LockMode::S
+            } else {
+                LockMode::X
+            };
+            match locks.iter_mut().find(|(locked, _)| *locked == entity) {
+                Some((_, locked_mode)) => {
+                    locked_mode.merge(mode);
+                }
+                None => locks.push((entity, mode)),
+            }
+        }
+        locks
+    }
+}
+
+/// Lock-wait latencies in microseconds, each kept within 1/16 of its value so that histograms stay
+/// small and merge by adding up counts.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct WaitHistogram {
+    counts: BTreeMap<u32, u64>,
+}
+
+impl WaitHistogram {
+    /// Latencies below 16µs get a bucket each; above that, every power of two is split into 16.
+    fn bucket(micros: u64) -> u32 {
+        if micros < 16 {
+            return micros as u32;
+        }
+        let exponent = 63 - micros.leading_zeros();
+        let mantissa = (micros >> (exponent - 4)) as u32 - 16;
+        16 * (exponent - 3) + mantissa
+    }
+
+    /// The smallest latency in `bucket`.
+    fn lower_bound(bucket: u32) -> Duration {
+        if bucket < 16 {
+            return Duration::from_micros(bucket as u64);
+        }
+        let (exponent, mantissa) = (bucket / 16 + 3, bucket % 16);
+        Duration::from_micros(((16 + mantissa) as u64) << (exponent - 4))
+    }
+
+    pub fn record(&mut self, wait: Duration) {
+        *self
+            .counts
+            .entry(Self::bucket(wait.as_micros() as u64))
+            .or_default() += 1;
+    }
+
+    pub fn len(&self) -> u64 {
+        self.counts.values().sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.counts.is_empty()
+    }
+
+    /// The latency that a `quantile` of the waits, between 0 and 1, took at most, rounded down to
+    /// its bucket.
+    pub fn quantile(&self, quantile: f64) -> Duration {
+        let rank = ((self.len() as f64 * quantile).ceil() as u64).max(1);
+        let mut seen = 0;
+        for (bucket, count) in &self.counts {
+            seen += count;
+            if seen >= rank {
+                return Self::lower_bound(*bucket);
+            }
+        }
+        Duration::ZERO
+    }
+
+    pub fn merge(&mut self, other: &Self) {
+        for (bucket, count) in &other.counts {
+            *self.counts.entry(*bucket).or_default() += count;
+        }
+    }
+}
+
+/// Writes the buckets as `bucket:count` pairs separated by commas, see [`WaitHistogram::from_str`].
+impl fmt::Display for WaitHistogram {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let buckets = self
+            .counts
+            .iter()
+            .map(|(bucket, count)| format!("{}:{}", bucket, count))
+            .collect::<Vec<_>>();
+        write!(f, "{}", buckets.join(","))
+    }
+}
+
+impl FromStr for WaitHistogram {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let counts = s
+            .split(',')
+            .filter(|pair| !pair.is_empty())
+            .map(|pair| {
+                let (bucket, count) = pair
+                    .split_once(':')
+                    .ok_or_else(|| format!("no count in {:?}", pair))?;
+                Ok((
+                    bucket
+                        .parse()
+                        .map_err(|_| format!("bad bucket {:?}", bucket))?,
+                    count
+                        .parse()
+                        .map_err(|_| format!("bad count {:?}", count))?,
+                ))
+            })
+            .collect::<Result<_, String>>()?;
+        Ok(Self { counts })
+    }
+}
+
+/// What a run of the workload did, on one client member or summed over several.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct WorkloadStats {
+    pub committed: u64,
+    /// Transactions that ended without committing: aborted by the service, or given up by the
+    /// client after an acquire was denied or timed out.
+    pub aborted: u64,
+    /// How long the run took. Runs on several members overlap, so merging keeps the longest.
+    pub elapsed: Duration,
+    /// How long each granted acquire took, from sending it to the grant.
+    pub waits: WaitHistogram,
+}
+
+/// The line a client member prints its stats on, for the harness to pick up.
+const STATS_PREFIX: &str = "workload stats:";
+
+impl WorkloadStats {
+    /// Committed transactions per second.
+    pub fn throughput(&self) -> f64 {
+        self.committed as f64 / self.elapsed.as_secs_f64().max(f64::MIN_POSITIVE)
+    }
+
+    /// The share of transactions that did not commit.
+    pub fn abort_rate(&self) -> f64 {
+        let ended = self.committed + self.aborted;
+        if ended == 0 {
+            0.0
+        } else {
+            self.aborted as f64 / ended as f64
+        }
+    }
+
+    pub fn merge(&mut self, other: &Self) {
+        self.committed += other.committed;
+        self.aborted += other.aborted;
+        self.elapsed = self.elapsed.max(other.elapsed);
+        self.waits.merge(&other.waits);
+    }
+
+    /// A single line that [`WorkloadStats::from_line`] reads back.
+    pub fn to_line(&self) -> String {
+        format!(
+            "{} {} {} {} {}",
+            STATS_PREFIX,
+            self.committed,
+            self.aborted,
+            self.elapsed.as_micros(),
+            self.waits
+        )
+    }
+
+    /// Reads a line written by [`WorkloadStats::to_line`], or returns `None` for any other line.
+    pub fn from_line(line: &str) -> Option<Self> {
+        let mut fields = line.strip_prefix(STATS_PREFIX)?.split_whitespace();
+        let committed = fields.next()?.parse().ok()?;
+        let aborted = fields.next()?.parse().ok()?;
+        let elapsed = Duration::from_micros(fields.next()?.parse().ok()?);
+        // a run without grants has an empty histogram
+        let waits = fields.next().unwrap_or_default().parse().ok()?;
+        Some(Self {
+            committed,
+            aborted,
+            elapsed,
+            waits,
+        })
+    }
+}
+
+impl fmt::Display for WorkloadStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:.1} commits/s, {:.2}% aborted ({} committed, {} aborted in {:.1?}), \
+             lock wait p50 {:?}, p99 {:?}",
+            self.throughput(),
+            100.0 * self.abort_rate(),
+            self.committed,
+            self.aborted,
+            self.elapsed,
+            self.waits.quantile(0.5),
+            self.waits.quantile(0.99),
+        )
+    }
+}
+
+/// Runs one transaction to its end, counting it and its lock waits in `stats`.
+async fn run_transaction(
+    client: &LockClient,
+    locks: Vec<(EntityPath<EntityId>, LockMode)>,
+    stats: &mut WorkloadStats,
+) {
+    let key = client.begin().await;
+    for (entity, mode) in locks {
+        let sent = Instant::now();
+        match client.acquire(key, entity, mode).await {
+            Ok(()) => stats.waits.record(sent.elapsed()),
+            Err(_) => {
+                client.abort(key);
+                stats.aborted += 1;
+                return;
+            }
+        }
+    }
+    match client.commit(key).await {
+        Ok(()) => stats.committed += 1,
+        Err(_) => stats.aborted += 1,
+    }
+}
+
+/// Runs `config.clients` closed-loop clients against the lock service behind `client`, with
+/// `seed` picking their transactions, and returns what they did.
+pub async fn run_workload(client: LockClient, config: WorkloadConfig, seed: u64) -> WorkloadStats {
+    // the clock starts once the service answers
+    let key = client.begin().await;
+    client.commit(key).await.unwrap();
+
+    let generator = Arc::new(TransactionGenerator::new(&config));
+    let duration = config.duration;
+    let start = Instant::now();
+    let clients = (0..config.clients)
+        .map(|i| {
+            let client = client.clone();
+            let generator = generator.clone();
+            let mut rng = StdRng::seed_from_u64(seed.wrapping_add(i as u64));
+            tokio::spawn(async move {
+                let mut stats = WorkloadStats::default();
+                while start.elapsed() < duration {
+                    run_transaction(&client, generator.next(&mut rng), &mut stats).await;
+                }
+                stats
+            })
+        })
+        .collect::<Vec<_>>();
+
+    let mut stats = WorkloadStats::default();
+    for client in clients {
+        stats.merge(&client.await.unwrap());
+    }
+    stats.elapsed = start.elapsed();
+    stats
+}
+
+/// Runs the workload from [`WorkloadConfig::from_env`] on every client member, and prints its
+/// stats there once it is done.
+pub async fn workload_demo(client: LockClient) {
+    let stats = run_workload(client, WorkloadConfig::from_env(), rand::random()).await;
+    println!("{}", stats.to_line());
+}
+
+/// The lock service of [`first_ten_distributed`], set up from [`WorkloadConfig::from_env`] on
+/// every member, for clients that run [`workload_demo`].
+///
+/// [`first_ten_distributed`]: crate::first_ten_distributed::first_ten_distributed
+pub fn workload<'a, D: Deploy<'a>>(
+    flow: &'a FlowBuilder<'a, D>,
+    process_spec: &impl ProcessSpec<'a, D>,
+    cluster_spec: &impl ClusterSpec<'a, D>,
+    client_ports: RuntimeData<ClientPorts>,
+) -> D::Cluster {
+    serve_lock_clients(
+        flow,
+        process_spec,
+        cluster_spec,
+        client_ports,
+        q!(WorkloadConfig::from_env().victim_policy),
+        q!(WorkloadConfig::from_env().lock),
+        q!(std::env::var_os("LOCK_LOG_DIR").map(std::path::PathBuf::from)),
+    )
+}
+
+use hydroflow_plus::util::cli::HydroCLI;
+use hydroflow_plus_cli_integration::{CLIRuntime, HydroflowPlusMeta};
+
+#[stageleft::entry]
+pub fn workload_runtime<'a>(
+    flow: &'a FlowBuilder<'a, CLIRuntime>,
+    cli: RuntimeData<&'a HydroCLI<HydroflowPlusMeta>>,
+    client_ports: RuntimeData<ClientPorts>,
+) -> impl Quoted<'a, Hydroflow<'a>> {
+    workload(flow, &cli, &cli, client_ports);
+    flow.build(q!(cli.meta.subgraph_id))
+}
+
+#[stageleft::runtime]
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use rand::rngs::StdRng;
+    use rand::SeedableRng;
+
+    use super::{TransactionGenerator, WaitHistogram, WorkloadConfig, WorkloadStats};
+    use crate::first_ten_distributed::EntityId;
+    use crate::hierarchy::EntityPath;
+
+    #[test]
+    fn skews_towards_the_first_entities() {
+        let config = WorkloadConfig {
+            entities: 100,
+            zipf_skew: 1.5,
+            transaction_length: 1,
+            hierarchy_depth: 2,
+            ..Default::default()
+        };
+        let generator = TransactionGenerator::new(&config);
+        let mut rng = StdRng::seed_from_u64(0);
+        let hot = (0..1000)
+            .filter(|_| generator.next(&mut rng)[0].0 == EntityPath(vec![EntityId(0), EntityId(0)]))
+            .count();
+        // the first of 100 entities has about 38% of the weight
+        assert!((300..460).contains(&hot), "{}", hot);
+
+        assert_eq!(
+            generator.path(57),
+            EntityPath(vec![EntityId(5), EntityId(7)])
+        );
+    }
+
+    #[test]
+    fn histogram_keeps_quantiles() {
+        let mut waits = WaitHistogram::default();
+        for micros in 1..=1000 {
+            waits.record(Duration::from_micros(micros));
+        }
+        let p50 = waits.quantile(0.5).as_micros();
+        assert!((500 - 500 / 16..=500).contains(&p50), "{}", p50);
+        let p99 = waits.quantile(0.99).as_micros();
+        assert!((990 - 990 / 16..=990).contains(&p99), "{}", p99);
+    }
+
+    #[test]
+    fn stats_round_trip_through_lines() {
+        let mut stats = WorkloadStats {
+            committed: 10,
+            aborted: 2,
+            elapsed: Duration::from_millis(1500),
+            ..Default::default()
+        };
+        assert_eq!(
+            WorkloadStats::from_line(&stats.to_line()),
+            Some(stats.clone())
+        );
+
+        stats.waits.record(Duration::from_micros(3));
+        stats.waits.record(Duration::from_millis(7));
+        let mut merged = WorkloadStats::from_line(&stats.to_line()).unwrap();
+        assert_eq!(merged, stats);
+        merged.merge(&stats);
+        assert_eq!((merged.committed, merged.waits.len()), (20, 4));
+        assert_eq!(WorkloadStats::from_line("Ok(())"), None);
+    }
+}